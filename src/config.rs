@@ -0,0 +1,697 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    file_manager::{FileManager, FileManagerError},
+    header,
+    method::Method,
+    request::Request,
+    response::{Response, ResponseBuilder, StatusCode},
+    router::RouteError,
+    server::ServerHTTP,
+};
+
+/// Methods a redirect is registered for. HEAD is answered by the GET route.
+const REDIRECT_METHODS: [Method; 5] = [
+    Method::Get,
+    Method::Post,
+    Method::Put,
+    Method::Patch,
+    Method::Delete,
+];
+
+/// Settings of a server read from a TOML file, so a deployment can be reproduced without
+/// recompiling. Every setting is optional, the ones missing keep the server defaults.
+///
+/// ```toml
+/// listen = ["0.0.0.0:8080", "[::]:8080"]
+/// directory = "/srv/files"
+/// workers = 32
+/// compression = true
+///
+/// [timeouts]
+/// read = 30
+/// write = 30
+/// keep_alive = 5
+/// drain = 10
+///
+/// [limits]
+/// max_body_size = 1048576
+///
+/// [headers]
+/// X-Frame-Options = "DENY"
+///
+/// [[static]]
+/// prefix = "/assets"
+/// directory = "/srv/assets"
+///
+/// [[redirect]]
+/// from = "/old"
+/// to = "/new"
+/// status = 308
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to listen on, e.g. `0.0.0.0:80` or `[::]:80`
+    pub listen: Vec<String>,
+    /// Public folder given to handlers in `res.public_folder`
+    pub directory: Option<String>,
+    pub workers: Option<usize>,
+    pub queue_depth: Option<usize>,
+    pub compression: Option<bool>,
+    pub timeouts: TimeoutsConfig,
+    pub limits: LimitsConfig,
+    /// Headers added to every response that does not set them already
+    pub headers: BTreeMap<String, String>,
+    #[serde(rename = "static")]
+    pub static_mounts: Vec<StaticMount>,
+    #[serde(rename = "redirect")]
+    pub redirects: Vec<Redirect>,
+}
+
+/// Timeouts in seconds, fractions allowed
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub read: Option<f64>,
+    pub write: Option<f64>,
+    pub keep_alive: Option<f64>,
+    pub drain: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_uri_length: Option<usize>,
+    pub max_headers_size: Option<usize>,
+    pub max_body_size: Option<usize>,
+    pub max_requests_per_connection: Option<usize>,
+}
+
+/// Files of a directory served under a URL prefix, e.g. `/assets/site.css` is
+/// `/srv/assets/site.css`. Directories are answered with their `index.html`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticMount {
+    pub prefix: String,
+    pub directory: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Redirect {
+    /// Route pattern of the redirected paths
+    pub from: String,
+    /// Location the client is sent to
+    pub to: String,
+    /// One of 301, 302, 303, 307 or 308, 301 Moved Permanently by default
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    Parse(toml::de::Error),
+    /// The field is given as its path in the file, e.g. `static[1].prefix`
+    InvalidValue {
+        field: String,
+        reason: String,
+    },
+    Route {
+        field: String,
+        source: RouteError,
+    },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(
+                    f,
+                    "Error reading the configuration file {:?}: {}",
+                    path, source
+                )
+            }
+            ConfigError::Parse(err) => write!(f, "Invalid configuration file: {}", err),
+            ConfigError::InvalidValue { field, reason } => {
+                write!(f, "Invalid configuration value for `{}`: {}", field, reason)
+            }
+            ConfigError::Route { field, source } => {
+                write!(f, "Invalid configuration route for `{}`: {}", field, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            ConfigError::Parse(err) => Some(err),
+            ConfigError::InvalidValue { .. } | ConfigError::Route { .. } => None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// It reads and validates the configuration file
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.display().to_string(),
+            source,
+        })?;
+
+        Self::parse(&content)
+    }
+
+    /// It parses and validates the configuration. Unknown settings are rejected, so a typo is
+    /// reported instead of silently keeping the default.
+    pub fn parse(content: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(content).map_err(ConfigError::Parse)?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// It returns the `listen` addresses replacing their host or port when given, e.g. by
+    /// command line options that override only part of them. Addresses that end up the same
+    /// are returned once.
+    pub fn listen_addresses(&self, host: Option<&str>, port: Option<u16>) -> Vec<String> {
+        let mut addresses: Vec<String> = vec![];
+
+        for address in &self.listen {
+            let address = match address.rsplit_once(':') {
+                Some((listen_host, listen_port)) if host.is_some() || port.is_some() => {
+                    let host = host
+                        .unwrap_or(listen_host)
+                        .trim_start_matches('[')
+                        .trim_end_matches(']');
+                    let port = port.map_or(listen_port.to_string(), |port| port.to_string());
+
+                    // IPv6 addresses are enclosed in brackets, as their colons are not a port
+                    // separator
+                    if host.contains(':') {
+                        format!("[{}]:{}", host, port)
+                    } else {
+                        format!("{}:{}", host, port)
+                    }
+                }
+                _ => address.clone(),
+            };
+
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+
+        addresses
+    }
+
+    /// It checks the values that parse but can not be used, e.g. a timeout of -1 seconds
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (i, address) in self.listen.iter().enumerate() {
+            let resolves = address
+                .to_socket_addrs()
+                .is_ok_and(|mut addresses| addresses.next().is_some());
+
+            if !resolves {
+                return Err(invalid(
+                    format!("listen[{}]", i),
+                    format!("{:?} is not an address with a port", address),
+                ));
+            }
+        }
+
+        if self.workers == Some(0) {
+            return Err(invalid("workers", "it must be at least 1"));
+        }
+
+        let timeouts = [
+            ("timeouts.read", self.timeouts.read, false),
+            ("timeouts.write", self.timeouts.write, false),
+            ("timeouts.keep_alive", self.timeouts.keep_alive, false),
+            ("timeouts.drain", self.timeouts.drain, true),
+        ];
+
+        for (field, seconds, allows_zero) in timeouts {
+            let Some(seconds) = seconds else {
+                continue;
+            };
+
+            if Duration::try_from_secs_f64(seconds).is_err() {
+                return Err(invalid(
+                    field,
+                    format!("{} is not a number of seconds", seconds),
+                ));
+            }
+
+            if seconds == 0.0 && !allows_zero {
+                return Err(invalid(field, "it must be greater than 0"));
+            }
+        }
+
+        let limits = [
+            ("limits.max_uri_length", self.limits.max_uri_length),
+            ("limits.max_headers_size", self.limits.max_headers_size),
+            (
+                "limits.max_requests_per_connection",
+                self.limits.max_requests_per_connection,
+            ),
+        ];
+
+        for (field, limit) in limits {
+            if limit == Some(0) {
+                return Err(invalid(field, "it must be at least 1"));
+            }
+        }
+
+        for (name, value) in &self.headers {
+            if !header::is_valid_name(name) {
+                return Err(invalid(
+                    format!("headers.{:?}", name),
+                    "it is not a valid header name",
+                ));
+            }
+
+            if !header::is_valid_value(value) {
+                return Err(invalid(
+                    format!("headers.{:?}", name),
+                    "header values can not contain line breaks or NUL",
+                ));
+            }
+        }
+
+        for (i, mount) in self.static_mounts.iter().enumerate() {
+            if !mount.prefix.starts_with('/') || mount.prefix.contains(['{', '}']) {
+                return Err(invalid(
+                    format!("static[{}].prefix", i),
+                    format!("{:?} is not a path starting with '/'", mount.prefix),
+                ));
+            }
+
+            if !Path::new(&mount.directory).is_dir() {
+                return Err(invalid(
+                    format!("static[{}].directory", i),
+                    format!("{:?} is not a directory", mount.directory),
+                ));
+            }
+        }
+
+        for (i, redirect) in self.redirects.iter().enumerate() {
+            if !redirect.from.starts_with('/') {
+                return Err(invalid(
+                    format!("redirect[{}].from", i),
+                    format!("{:?} is not a path starting with '/'", redirect.from),
+                ));
+            }
+
+            if redirect.to.is_empty() || !header::is_valid_value(&redirect.to) {
+                return Err(invalid(
+                    format!("redirect[{}].to", i),
+                    format!("{:?} is not a valid location", redirect.to),
+                ));
+            }
+
+            if !matches!(redirect.status, 301 | 302 | 303 | 307 | 308) {
+                return Err(invalid(
+                    format!("redirect[{}].status", i),
+                    format!(
+                        "{} is not one of 301, 302, 303, 307 or 308",
+                        redirect.status
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// It applies the settings to the server and registers the static mounts and redirects. The
+    /// `listen` addresses are left to the caller to bind. It fails when a route conflicts with
+    /// one already registered.
+    pub fn apply(&self, server: &mut ServerHTTP) -> Result<(), ConfigError> {
+        self.validate()?;
+
+        if let Some(directory) = &self.directory {
+            server.set_public_folder(directory);
+        }
+
+        if let Some(workers) = self.workers {
+            server.set_workers(workers);
+        }
+
+        if let Some(queue_depth) = self.queue_depth {
+            server.set_queue_depth(queue_depth);
+        }
+
+        if let Some(compression) = self.compression {
+            server.set_compression(compression);
+        }
+
+        if let Some(read) = self.timeouts.read {
+            server.set_read_timeout(Duration::from_secs_f64(read));
+        }
+
+        if let Some(write) = self.timeouts.write {
+            server.set_write_timeout(Duration::from_secs_f64(write));
+        }
+
+        if let Some(keep_alive) = self.timeouts.keep_alive {
+            server.set_keep_alive_timeout(Duration::from_secs_f64(keep_alive));
+        }
+
+        if let Some(drain) = self.timeouts.drain {
+            server.set_drain_timeout(Duration::from_secs_f64(drain));
+        }
+
+        let mut limits = server.get_request_limits().clone();
+
+        limits.max_uri_length = self.limits.max_uri_length.unwrap_or(limits.max_uri_length);
+        limits.max_headers_size = self
+            .limits
+            .max_headers_size
+            .unwrap_or(limits.max_headers_size);
+        limits.max_body_size = self.limits.max_body_size.unwrap_or(limits.max_body_size);
+
+        server.set_request_limits(limits);
+
+        if let Some(max_requests) = self.limits.max_requests_per_connection {
+            server.set_max_requests_per_connection(max_requests);
+        }
+
+        for (i, mount) in self.static_mounts.iter().enumerate() {
+            let path = format!("{}/{{*path}}", mount.prefix.trim_end_matches('/'));
+            let directory = mount.directory.clone();
+
+            server
+                .try_handle(Method::Get, &path, move |req: Request| {
+                    serve_static_file(&directory, &req.param::<String>("path").unwrap_or_default())
+                })
+                .map_err(|source| ConfigError::Route {
+                    field: format!("static[{}].prefix", i),
+                    source,
+                })?;
+        }
+
+        for (i, redirect) in self.redirects.iter().enumerate() {
+            for method in REDIRECT_METHODS {
+                let location = redirect.to.clone();
+                let status = redirect.status;
+
+                server
+                    .try_handle(method, &redirect.from, move |_| {
+                        let status_code =
+                            StatusCode::from_u16(status).unwrap_or(StatusCode::MovedPermanently);
+
+                        Ok::<_, StatusCode>(
+                            ResponseBuilder::new()
+                                .build()
+                                .status_code(status_code)
+                                .header("Location", &location),
+                        )
+                    })
+                    .map_err(|source| ConfigError::Route {
+                        field: format!("redirect[{}].from", i),
+                        source,
+                    })?;
+            }
+        }
+
+        if !self.headers.is_empty() {
+            let headers = self.headers.clone();
+
+            server.use_middleware(move |req, next| {
+                let mut res = next.run(req);
+
+                for (name, value) in &headers {
+                    if !res.get_headers().contains_key(name) {
+                        res.headers_mut().insert(name, value);
+                    }
+                }
+
+                res
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn default_redirect_status() -> u16 {
+    StatusCode::MovedPermanently.as_u16()
+}
+
+fn invalid(field: impl Into<String>, reason: impl Into<String>) -> ConfigError {
+    ConfigError::InvalidValue {
+        field: field.into(),
+        reason: reason.into(),
+    }
+}
+
+/// It answers with the file of the directory, or with its `index.html` for directories
+fn serve_static_file(directory: &str, path: &str) -> Result<Response, FileManagerError> {
+    let mut file_path = match path {
+        "" => PathBuf::from(directory),
+        path => FileManager::safe_join(directory, path)?,
+    };
+
+    if file_path.is_dir() {
+        file_path.push("index.html");
+    }
+
+    let content_type = FileManager::content_type(&file_path);
+    let read_result = FileManager::read(file_path)?;
+
+    Ok(ResponseBuilder::new()
+        .with_header("Content-Type", content_type)
+        .build()
+        .body(&read_result.content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_replaces_only_the_host_or_the_port_of_the_listen_addresses() {
+        let config =
+            ServerConfig::parse(r#"listen = ["0.0.0.0:8080", "[::]:8080", "localhost:9000"]"#)
+                .unwrap();
+
+        assert_eq!(
+            config.listen_addresses(None, None),
+            ["0.0.0.0:8080", "[::]:8080", "localhost:9000"]
+        );
+        assert_eq!(
+            config.listen_addresses(None, Some(80)),
+            ["0.0.0.0:80", "[::]:80", "localhost:80"]
+        );
+        assert_eq!(
+            config.listen_addresses(Some("::1"), None),
+            ["[::1]:8080", "[::1]:9000"]
+        );
+        assert_eq!(
+            config.listen_addresses(Some("127.0.0.1"), Some(80)),
+            ["127.0.0.1:80"]
+        );
+    }
+
+    /// It returns the field of the `InvalidValue` error the configuration fails with
+    fn invalid_field(content: &str) -> String {
+        match ServerConfig::parse(content) {
+            Err(ConfigError::InvalidValue { field, .. }) => field,
+            result => panic!(
+                "Expected an invalid value for {:?}, got {:?}",
+                content, result
+            ),
+        }
+    }
+
+    /// It returns a `[[static]]` entry serving a directory that exists
+    fn static_mount(prefix: &str) -> String {
+        format!(
+            "[[static]]\nprefix = {:?}\ndirectory = '{}'\n",
+            prefix,
+            std::env::temp_dir().display()
+        )
+    }
+
+    #[test]
+    fn it_rejects_unknown_keys() {
+        for content in [
+            "worker = 4",
+            "[timeouts]\nreed = 1",
+            "[limits]\nmax_body = 1",
+            "[[static]]\nprefix = '/a'\ndirectory = '/tmp'\nindex = true",
+            "[[redirect]]\nfrom = '/a'\nto = '/b'\ncode = 308",
+        ] {
+            assert!(
+                matches!(ServerConfig::parse(content), Err(ConfigError::Parse(_))),
+                "{}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_zero_or_negative_timeouts() {
+        assert_eq!(invalid_field("[timeouts]\nread = 0"), "timeouts.read");
+        assert_eq!(invalid_field("[timeouts]\nwrite = 0.0"), "timeouts.write");
+        assert_eq!(
+            invalid_field("[timeouts]\nkeep_alive = -1"),
+            "timeouts.keep_alive"
+        );
+        assert_eq!(invalid_field("[timeouts]\ndrain = -0.5"), "timeouts.drain");
+        assert_eq!(invalid_field("[timeouts]\nread = nan"), "timeouts.read");
+
+        // Not waiting for in-flight requests at all is allowed
+        assert!(ServerConfig::parse("[timeouts]\ndrain = 0").is_ok());
+    }
+
+    #[test]
+    fn it_rejects_zero_workers_and_limits() {
+        assert_eq!(invalid_field("workers = 0"), "workers");
+        assert_eq!(
+            invalid_field("[limits]\nmax_headers_size = 0"),
+            "limits.max_headers_size"
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_header_names_and_values() {
+        assert_eq!(
+            invalid_field("[headers]\n\"X Frame\" = \"DENY\""),
+            "headers.\"X Frame\""
+        );
+        assert_eq!(invalid_field("[headers]\n\"\" = \"DENY\""), "headers.\"\"");
+        assert_eq!(
+            invalid_field("[headers]\nX-Frame-Options = \"DENY\\r\\nX-Injected: 1\""),
+            "headers.\"X-Frame-Options\""
+        );
+        assert_eq!(
+            invalid_field("[headers]\nX-Frame-Options = \"DE\\u0000NY\""),
+            "headers.\"X-Frame-Options\""
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_redirects() {
+        let redirect = |from: &str, to: &str, status: u16| {
+            format!(
+                "[[redirect]]\nfrom = '/ok'\nto = '/new'\n[[redirect]]\nfrom = {:?}\nto = {:?}\nstatus = {}",
+                from, to, status
+            )
+        };
+
+        assert_eq!(
+            invalid_field(&redirect("/old", "/new", 200)),
+            "redirect[1].status"
+        );
+        assert_eq!(
+            invalid_field(&redirect("/old", "/new", 304)),
+            "redirect[1].status"
+        );
+        assert_eq!(
+            invalid_field(&redirect("old", "/new", 308)),
+            "redirect[1].from"
+        );
+        assert_eq!(invalid_field(&redirect("/old", "", 308)), "redirect[1].to");
+        assert_eq!(
+            invalid_field(&redirect("/old", "/new\nX: 1", 308)),
+            "redirect[1].to"
+        );
+
+        let config = ServerConfig::parse("[[redirect]]\nfrom = '/old'\nto = '/new'").unwrap();
+
+        assert_eq!(config.redirects[0].status, 301);
+    }
+
+    #[test]
+    fn it_reports_the_field_path_of_invalid_static_mounts() {
+        let content = format!("{}{}", static_mount("/assets"), static_mount("assets"));
+
+        assert_eq!(invalid_field(&content), "static[1].prefix");
+        assert_eq!(
+            ServerConfig::parse(&content).unwrap_err().to_string(),
+            "Invalid configuration value for `static[1].prefix`: \"assets\" is not a path starting with '/'"
+        );
+
+        let content = format!(
+            "{}{}",
+            static_mount("/assets"),
+            static_mount("/files/{name}")
+        );
+
+        assert_eq!(invalid_field(&content), "static[1].prefix");
+
+        let content = format!(
+            "{}[[static]]\nprefix = '/files'\ndirectory = '/does/not/exist'\n",
+            static_mount("/assets")
+        );
+
+        assert_eq!(invalid_field(&content), "static[1].directory");
+
+        assert_eq!(invalid_field("listen = ['localhost']"), "listen[0]");
+    }
+
+    #[test]
+    fn it_reports_static_mounts_that_conflict_with_other_routes() {
+        let config = ServerConfig::parse(&format!(
+            "{}{}",
+            static_mount("/assets"),
+            static_mount("/assets/")
+        ))
+        .unwrap();
+
+        let result = config.apply(&mut ServerHTTP::default());
+
+        assert!(
+            matches!(&result, Err(ConfigError::Route { field, .. }) if field == "static[1].prefix"),
+            "{:?}",
+            result
+        );
+
+        // The catch-all of the mount conflicts with a route already registered by the server
+        let mut server = ServerHTTP::default();
+        server.handle_fn(Method::Get, "/files/{*rest}", |_, res| res.send());
+
+        let config = ServerConfig::parse(&static_mount("/files")).unwrap();
+        let result = config.apply(&mut server);
+
+        assert!(
+            matches!(&result, Err(ConfigError::Route { field, .. }) if field == "static[0].prefix"),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn it_reports_redirects_that_conflict_with_other_routes() {
+        let config = ServerConfig::parse(
+            "[[redirect]]\nfrom = '/old'\nto = '/a'\n[[redirect]]\nfrom = '/old'\nto = '/b'",
+        )
+        .unwrap();
+
+        let result = config.apply(&mut ServerHTTP::default());
+
+        assert!(
+            matches!(&result, Err(ConfigError::Route { field, .. }) if field == "redirect[1].from"),
+            "{:?}",
+            result
+        );
+    }
+}
@@ -2,12 +2,15 @@ use anyhow::Context;
 use std::{
     fs::{self, File},
     io::{BufReader, Read, Write},
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
 };
 
+use crate::response::{IntoResponse, Response, StatusCode};
+
 #[derive(Debug)]
 pub enum FileManagerError {
     NotFound,
+    InvalidPath,
     Uknown(anyhow::Error),
 }
 
@@ -17,6 +20,9 @@ impl std::fmt::Display for FileManagerError {
             FileManagerError::NotFound => {
                 write!(f, "File not found")
             }
+            FileManagerError::InvalidPath => {
+                write!(f, "Path escapes the base directory")
+            }
             FileManagerError::Uknown(err) => {
                 write!(f, "{}\n\t{}", err, err.root_cause())
             }
@@ -24,15 +30,80 @@ impl std::fmt::Display for FileManagerError {
     }
 }
 
+impl IntoResponse for FileManagerError {
+    fn into_response(self) -> Response {
+        match self {
+            FileManagerError::NotFound => StatusCode::NotFound.into_response(),
+            FileManagerError::InvalidPath => StatusCode::BadRequest.into_response(),
+            FileManagerError::Uknown(err) => err.into_response(),
+        }
+    }
+}
+
 pub struct ReadResult {
-    pub content: String,
+    pub content: Vec<u8>,
     pub bytes_read: usize,
 }
 
+impl ReadResult {
+    /// It returns the content as text, failing when the file is not valid UTF-8
+    pub fn text(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.content)
+    }
+}
+
 #[derive(Debug)]
 pub struct FileManager;
 
 impl FileManager {
+    /// It joins a client provided path to a base directory, rejecting absolute paths and parent
+    /// (`..`) segments so the result can not point outside of the directory.
+    pub fn safe_join(directory: &str, relative_path: &str) -> Result<PathBuf, FileManagerError> {
+        let is_safe = Path::new(relative_path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+        if !is_safe || relative_path.is_empty() {
+            return Err(FileManagerError::InvalidPath);
+        }
+
+        let mut path = PathBuf::from(directory);
+
+        path.push(relative_path);
+
+        Ok(path)
+    }
+
+    /// It returns the media type of the file guessed from its extension, `application/octet-stream`
+    /// when it is not known
+    pub fn content_type(path: &Path) -> &'static str {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        match extension.as_str() {
+            "html" | "htm" => "text/html; charset=utf-8",
+            "css" => "text/css; charset=utf-8",
+            "js" | "mjs" => "text/javascript; charset=utf-8",
+            "json" => "application/json",
+            "txt" => "text/plain; charset=utf-8",
+            "xml" => "application/xml",
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "svg" => "image/svg+xml",
+            "webp" => "image/webp",
+            "ico" => "image/x-icon",
+            "pdf" => "application/pdf",
+            "wasm" => "application/wasm",
+            "woff" => "font/woff",
+            "woff2" => "font/woff2",
+            _ => "application/octet-stream",
+        }
+    }
+
     pub fn read(path: PathBuf) -> Result<ReadResult, FileManagerError> {
         let exists = path
             .try_exists()
@@ -48,10 +119,10 @@ impl FileManager {
             .map_err(FileManagerError::Uknown)?;
 
         let mut reader = BufReader::new(file);
-        let mut content = String::new();
+        let mut content: Vec<u8> = vec![];
 
         let bytes_read = reader
-            .read_to_string(&mut content)
+            .read_to_end(&mut content)
             .context(format!("Read file from path {:?}", path))
             .map_err(FileManagerError::Uknown)?;
 
@@ -61,21 +132,18 @@ impl FileManager {
         })
     }
 
-    pub fn write(directory: &str, filename: &str, content: &str) -> Result<(), FileManagerError> {
+    pub fn write(directory: &str, filename: &str, content: &[u8]) -> Result<(), FileManagerError> {
         fs::create_dir_all(directory)
             .with_context(|| format!("Create {:?} directory", directory))
             .map_err(FileManagerError::Uknown)?;
 
-        let mut file_path = PathBuf::new();
-
-        file_path.push(directory);
-        file_path.push(filename);
+        let file_path = Self::safe_join(directory, filename)?;
 
         let mut file = File::create(&file_path)
             .with_context(|| format!("Create {:?} file", file_path))
             .map_err(FileManagerError::Uknown)?;
 
-        file.write_all(content.as_bytes())
+        file.write_all(content)
             .with_context(|| format!("Write {:?} file", filename))
             .map_err(FileManagerError::Uknown)?;
 
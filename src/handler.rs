@@ -1,62 +1,85 @@
-use crate::{request::Request, response::Response};
+use std::sync::Arc;
 
-pub type HandlerFn = fn(Request, Response);
+use crate::{
+    method::Method,
+    middleware::{MiddlewareFn, Next},
+    request::Request,
+    response::{IntoResponse, Response},
+};
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
-pub struct HandlerPattern(pub String, pub String);
+/// Handlers are shared by every connection thread, so they can be closures capturing any data
+/// that is safe to share between threads.
+pub type HandlerFn = Arc<dyn Fn(Request, Response) + Send + Sync + 'static>;
 
-impl HandlerPattern {
-    pub fn get_method(&self) -> String {
-        self.0.clone()
-    }
-
-    pub fn get_path(&self) -> String {
-        self.1.clone()
-    }
+/// It adapts a handler that returns its response instead of sending it. Whatever it returns is
+/// sent, and errors are converted with `IntoResponse`, so every request gets exactly one response.
+pub(crate) fn returning_handler<F, E>(handler: F) -> HandlerFn
+where
+    F: Fn(Request) -> Result<Response, E> + Send + Sync + 'static,
+    E: IntoResponse,
+{
+    Arc::new(move |req, res| res.send_instead(handler(req).into_response()))
 }
 
-impl HandlerPattern {
-    pub fn contains_pattern(&self, request: &Request) -> bool {
-        let method = self.get_method();
-        let path = self.get_path();
+/// A registered handler together with the middlewares that only run for it
+#[derive(Clone)]
+pub struct Route {
+    pub(crate) handler: HandlerFn,
+    pub(crate) middlewares: Vec<MiddlewareFn>,
+    pub(crate) name: Option<String>,
+}
 
-        if method != request.method {
-            return false;
+impl Route {
+    pub(crate) fn new(handler: HandlerFn) -> Self {
+        Self {
+            handler,
+            middlewares: vec![],
+            name: None,
         }
+    }
 
-        if !path.starts_with('/') {
-            return false;
-        }
+    /// It names the route, so its URL can be built with `ServerHTTP::url_for`
+    pub fn with_name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.to_string());
 
-        if path == "/" && request.path == "/" {
-            return true;
-        }
+        self
+    }
 
-        let path_values: Vec<&str> = path.split('/').collect();
-        let req_path_values: Vec<&str> = request.path.split('/').collect();
+    /// It adds a middleware that runs only for this route, after the server and router
+    /// middlewares
+    pub fn with_middleware<F>(&mut self, middleware: F) -> &mut Self
+    where
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static,
+    {
+        self.middlewares.push(Arc::new(middleware));
 
-        if path_values.len() != req_path_values.len() {
-            return false;
-        }
+        self
+    }
+}
 
-        // It analyzes each fragment of the url and checks if it matches with the current pattern
-        for (index, pattern_value) in path_values.into_iter().enumerate() {
-            // It consider the pattern fragment equals to the request fragment because we know that
-            // when a path fragment starts with '{' and end with '}' it's a path parameter.
-            // e.g. /echo/{str} == /echo/abc
-            if pattern_value
-                .strip_prefix('{')
-                .and_then(|word| word.strip_suffix('}'))
-                .is_some()
-            {
-                continue;
-            }
-
-            if pattern_value != *req_path_values.get(index).unwrap() {
-                return false;
-            }
+impl std::fmt::Debug for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(
+                f,
+                "Route({:?}, {} middlewares)",
+                name,
+                self.middlewares.len()
+            ),
+            None => write!(f, "Route({} middlewares)", self.middlewares.len()),
         }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct HandlerPattern(pub Method, pub String);
 
-        true
+impl HandlerPattern {
+    pub fn get_method(&self) -> Method {
+        self.0.clone()
+    }
+
+    pub fn get_path(&self) -> String {
+        self.1.clone()
     }
 }
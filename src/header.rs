@@ -0,0 +1,92 @@
+use crate::request::is_token_char;
+
+/// Collection of HTTP header fields. Names are compared case-insensitively and every value is
+/// kept in the order it was added, so repeated fields such as `Set-Cookie` are not lost.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap(Vec<(String, String)>);
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// It returns the first value of the header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// It returns every value of the header in the order they were added
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// It adds a value to the header, keeping the values already present
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_string(), value.to_string()));
+    }
+
+    /// It sets the value of the header, replacing the values already present. The header keeps
+    /// the position where it was first added.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        match self
+            .0
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(name))
+        {
+            Some(position) => {
+                self.0[position].1 = value.to_string();
+
+                let mut index = 0;
+
+                self.0.retain(|(k, _)| {
+                    let keep = index <= position || !k.eq_ignore_ascii_case(name);
+
+                    index += 1;
+
+                    keep
+                });
+            }
+            None => self.append(name, value),
+        }
+    }
+
+    /// It removes every value of the header
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.0.iter().any(|(k, _)| k.eq_ignore_ascii_case(name))
+    }
+
+    /// It iterates over every header value in order. Repeated headers appear once per value.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// It tells if the name can be used as a header name, which must be a token (RFC 9110)
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_token_char)
+}
+
+/// It tells if the value can be sent in a header. A CR or LF would end the field and start
+/// another one, and NUL is never allowed.
+pub(crate) fn is_valid_value(value: &str) -> bool {
+    !value.contains(['\r', '\n', '\0'])
+}
@@ -1,6 +1,17 @@
+pub mod config;
 pub mod encoding;
 pub mod file_manager;
 pub mod handler;
+pub mod header;
+pub mod method;
+pub mod middleware;
 pub mod request;
 pub mod response;
+pub mod router;
 pub mod server;
+pub mod shutdown;
+pub mod state;
+pub mod status;
+pub mod url;
+pub mod virtual_host;
+mod worker_pool;
@@ -1,18 +1,100 @@
-use std::path::PathBuf;
+use std::{
+    net::TcpListener,
+    num::NonZeroUsize,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use clap::Parser;
 use codecrafters_http_server::{
+    config::ServerConfig,
     file_manager::{FileManager, FileManagerError},
+    method::Method,
     response::StatusCode,
-    server::ServerHTTP,
+    server::{ServerError, ServerHTTP},
 };
 
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 4221;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// TOML configuration file. The options given on the command line take precedence over it.
+    #[arg(short, long, env = "HTTP_SERVER_CONFIG", value_name = "FILE")]
+    config: Option<PathBuf>,
+
     /// Public server directory
-    #[arg(short, long)]
+    #[arg(short, long, env = "HTTP_SERVER_DIRECTORY")]
     directory: Option<String>,
+
+    /// Address to listen on [default: 127.0.0.1]. It replaces the host of the configuration
+    /// addresses.
+    #[arg(long, env = "HTTP_SERVER_HOST")]
+    host: Option<String>,
+
+    /// Port to listen on, 0 picks a free one [default: 4221]. It replaces the port of the
+    /// configuration addresses.
+    #[arg(short, long, env = "HTTP_SERVER_PORT")]
+    port: Option<u16>,
+
+    /// Address and port to listen on, e.g. `0.0.0.0:80` or `[::]:80`. It can be repeated to
+    /// listen on several addresses, and it replaces --host and --port.
+    #[arg(
+        long,
+        env = "HTTP_SERVER_BIND",
+        value_name = "ADDRESS",
+        value_delimiter = ','
+    )]
+    bind: Vec<String>,
+
+    /// Threads serving connections
+    #[arg(long, env = "HTTP_SERVER_WORKERS")]
+    workers: Option<NonZeroUsize>,
+
+    /// Maximum size of a request body in bytes
+    #[arg(long, env = "HTTP_SERVER_MAX_BODY_SIZE", value_name = "BYTES")]
+    max_body_size: Option<usize>,
+
+    /// Time a client has to send a complete request
+    #[arg(
+        long,
+        env = "HTTP_SERVER_READ_TIMEOUT",
+        value_name = "SECONDS",
+        value_parser = parse_positive_seconds
+    )]
+    read_timeout: Option<Duration>,
+
+    /// Time a client has to read a complete response
+    #[arg(
+        long,
+        env = "HTTP_SERVER_WRITE_TIMEOUT",
+        value_name = "SECONDS",
+        value_parser = parse_positive_seconds
+    )]
+    write_timeout: Option<Duration>,
+
+    /// Time an idle persistent connection is kept open
+    #[arg(
+        long,
+        env = "HTTP_SERVER_KEEP_ALIVE_TIMEOUT",
+        value_name = "SECONDS",
+        value_parser = parse_positive_seconds
+    )]
+    keep_alive_timeout: Option<Duration>,
+
+    /// Time in-flight requests get to finish when shutting down
+    #[arg(
+        long,
+        env = "HTTP_SERVER_DRAIN_TIMEOUT",
+        value_name = "SECONDS",
+        value_parser = parse_seconds
+    )]
+    drain_timeout: Option<Duration>,
+
+    /// Print the registered routes and exit
+    #[arg(long)]
+    list_routes: bool,
 }
 
 fn main() {
@@ -20,21 +102,40 @@ fn main() {
 
     let mut server = ServerHTTP::default();
 
-    if let Some(dir) = args.directory {
-        server.set_public_folder(dir.as_str());
-    }
+    let config = match &args.config {
+        Some(path) => ServerConfig::from_file(path).unwrap_or_else(|err| exit_with_error(err)),
+        None => ServerConfig::default(),
+    };
+
+    server.use_middleware(|req, next| {
+        let method = req.method.clone();
+        let path = req.path.clone();
+        let started_at = Instant::now();
+
+        let res = next.run(req);
+
+        println!(
+            "{} {} {} {:?}",
+            method,
+            path,
+            res.get_status_code().as_u16(),
+            started_at.elapsed()
+        );
 
-    server.handle_fn("GET", "/", |_, res| {
+        res
+    });
+
+    server.handle_fn(Method::Get, "/", |_, res| {
         res.send();
     });
 
-    server.handle_fn("GET", "/echo/{str}", |req, res| {
+    server.handle_fn(Method::Get, "/echo/{str}", |req, res| {
         let str_value = req.path_params.get("str");
 
         res.send_text(str_value.map(|value| value.as_str()).unwrap_or_default());
     });
 
-    server.handle_fn("GET", "/user-agent", |req, res| {
+    server.handle_fn(Method::Get, "/user-agent", |req, res| {
         if let Some(user_agent) = req.headers.get("User-Agent") {
             res.send_text(user_agent);
         } else {
@@ -42,40 +143,41 @@ fn main() {
         }
     });
 
-    server.handle_fn("GET", "/files/{filename}", |req, res| {
-        if let Some(filename) = req.path_params.get("filename") {
-            if res.public_folder.is_none() {
-                res.status_code(StatusCode::InternalServer).send();
-
-                return;
-            }
-
-            let mut path = PathBuf::new();
+    server
+        .handle_fn(Method::Get, "/files/{*filename}", |req, res| {
+            if let Some(filename) = req.path_params.get("filename") {
+                if res.public_folder.is_none() {
+                    res.status_code(StatusCode::InternalServer).send();
 
-            path.push(res.public_folder.as_ref().unwrap());
-            path.push(filename);
+                    return;
+                }
 
-            let result = FileManager::read(path);
+                let result = FileManager::safe_join(res.public_folder.as_ref().unwrap(), filename)
+                    .and_then(FileManager::read);
 
-            match result {
-                Ok(read_result) => {
-                    res.send_file(&read_result.content);
-                }
-                Err(err) => match err {
-                    FileManagerError::NotFound => {
-                        res.status_code(StatusCode::NotFound).send();
-                    }
-                    _ => {
-                        res.status_code(StatusCode::InternalServer).send();
+                match result {
+                    Ok(read_result) => {
+                        res.send_file(&read_result.content);
                     }
-                },
+                    Err(err) => match err {
+                        FileManagerError::NotFound => {
+                            res.status_code(StatusCode::NotFound).send();
+                        }
+                        FileManagerError::InvalidPath => {
+                            res.status_code(StatusCode::BadRequest).send();
+                        }
+                        _ => {
+                            res.status_code(StatusCode::InternalServer).send();
+                        }
+                    },
+                }
+            } else {
+                res.status_code(StatusCode::BadRequest).send();
             }
-        } else {
-            res.status_code(StatusCode::BadRequest).send();
-        }
-    });
+        })
+        .with_name("file");
 
-    server.handle_fn("POST", "/files/{filename}", |req, res| {
+    server.handle_fn(Method::Post, "/files/{filename}", |req, res| {
         if let Some(filename) = req.path_params.get("filename") {
             if res.public_folder.is_none() {
                 eprintln!("Missing public folder.");
@@ -87,12 +189,15 @@ fn main() {
 
             let public_folder = res.public_folder.as_ref().unwrap();
 
-            let result = FileManager::write(public_folder, filename, req.body.as_str());
+            let result = FileManager::write(public_folder, filename, &req.body);
 
             match result {
                 Ok(()) => {
                     res.status_code(StatusCode::Created).send();
                 }
+                Err(FileManagerError::InvalidPath) => {
+                    res.status_code(StatusCode::BadRequest).send();
+                }
                 Err(_) => {
                     res.status_code(StatusCode::InternalServer).send();
                 }
@@ -102,5 +207,157 @@ fn main() {
         }
     });
 
-    server.listen("127.0.0.1:4221".to_string());
+    // The configuration is applied before the command line options, so they override it
+    if let Err(err) = config.apply(&mut server) {
+        exit_with_error(err);
+    }
+
+    apply_args(&mut server, &args);
+
+    if args.list_routes {
+        print_routes(&server);
+
+        return;
+    }
+
+    let shutdown = bind_listeners(&args, &config)
+        .and_then(|listeners| server.serve_listeners(listeners))
+        .unwrap_or_else(|err| exit_with_error(err));
+
+    for local_addr in shutdown.local_addrs() {
+        println!("Listening on {}", local_addr);
+    }
+
+    let signal_shutdown = shutdown.clone();
+
+    let result = ctrlc::set_handler(move || {
+        // A second signal stops waiting for the in-flight requests
+        if signal_shutdown.is_shutting_down() {
+            std::process::exit(1);
+        }
+
+        println!("Shutting down, waiting for in-flight requests");
+
+        signal_shutdown.shutdown();
+    });
+
+    if let Err(err) = result {
+        eprintln!("Error handling termination signals: {}", err);
+    }
+
+    shutdown.wait();
+}
+
+/// It sets the server options given on the command line
+fn apply_args(server: &mut ServerHTTP, args: &Args) {
+    if let Some(dir) = &args.directory {
+        server.set_public_folder(dir.as_str());
+    }
+
+    if let Some(workers) = args.workers {
+        server.set_workers(workers.get());
+    }
+
+    if let Some(max_body_size) = args.max_body_size {
+        server.set_max_body_size(max_body_size);
+    }
+
+    if let Some(read_timeout) = args.read_timeout {
+        server.set_read_timeout(read_timeout);
+    }
+
+    if let Some(write_timeout) = args.write_timeout {
+        server.set_write_timeout(write_timeout);
+    }
+
+    if let Some(keep_alive_timeout) = args.keep_alive_timeout {
+        server.set_keep_alive_timeout(keep_alive_timeout);
+    }
+
+    if let Some(drain_timeout) = args.drain_timeout {
+        server.set_drain_timeout(drain_timeout);
+    }
+}
+
+/// It binds every --bind address. Without them it binds the configuration addresses, with their
+/// host or port replaced by --host and --port, or just --host and --port when the configuration
+/// has none.
+fn bind_listeners(args: &Args, config: &ServerConfig) -> Result<Vec<TcpListener>, ServerError> {
+    let addresses = if !args.bind.is_empty() {
+        args.bind.clone()
+    } else if !config.listen.is_empty() {
+        config.listen_addresses(args.host.as_deref(), args.port)
+    } else {
+        let host = args.host.as_deref().unwrap_or(DEFAULT_HOST);
+        let port = args.port.unwrap_or(DEFAULT_PORT);
+
+        return Ok(vec![ServerHTTP::bind((host, port))?]);
+    };
+
+    addresses
+        .iter()
+        .map(|address| ServerHTTP::bind(address.as_str()))
+        .collect()
+}
+
+fn exit_with_error(err: impl std::fmt::Display) -> ! {
+    eprintln!("{}", err);
+
+    std::process::exit(1);
+}
+
+/// It parses a duration given in seconds, e.g. `30` or `0.5`
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    let seconds: f64 = value
+        .parse()
+        .map_err(|_| format!("{:?} is not a number of seconds", value))?;
+
+    Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string())
+}
+
+/// It parses a duration given in seconds like `parse_seconds`, rejecting 0
+fn parse_positive_seconds(value: &str) -> Result<Duration, String> {
+    let duration = parse_seconds(value)?;
+
+    if duration.is_zero() {
+        return Err("it must be greater than 0".to_string());
+    }
+
+    Ok(duration)
+}
+
+fn print_routes(server: &ServerHTTP) {
+    let rows: Vec<[String; 5]> = server
+        .routes()
+        .into_iter()
+        .map(|route| {
+            [
+                route.host.unwrap_or_else(|| "*".to_string()),
+                route.method.to_string(),
+                route.path,
+                route.name.unwrap_or_else(|| "-".to_string()),
+                route.middlewares.to_string(),
+            ]
+        })
+        .collect();
+
+    let header = ["HOST", "METHOD", "PATH", "NAME", "MIDDLEWARES"].map(String::from);
+
+    let mut widths = [0; 5];
+
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell))
+            .collect();
+
+        println!("{}", cells.join("  ").trim_end());
+    }
 }
@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use crate::{request::Request, response::Response};
+
+/// A middleware receives the request and the rest of the pipeline. It can change the request
+/// before calling `next.run(req)`, change the response returned by it, or return its own
+/// response without calling `next` at all to stop the request from reaching the handler.
+pub type MiddlewareFn = Arc<dyn Fn(Request, Next) -> Response + Send + Sync + 'static>;
+
+/// The remaining part of the pipeline: the middlewares not run yet and the handler at the end
+pub struct Next<'a> {
+    middlewares: &'a [MiddlewareFn],
+    endpoint: &'a dyn Fn(Request) -> Response,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        middlewares: &'a [MiddlewareFn],
+        endpoint: &'a dyn Fn(Request) -> Response,
+    ) -> Self {
+        Self {
+            middlewares,
+            endpoint,
+        }
+    }
+
+    pub fn run(self, req: Request) -> Response {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => middleware(
+                req,
+                Next {
+                    middlewares,
+                    endpoint: self.endpoint,
+                },
+            ),
+            None => (self.endpoint)(req),
+        }
+    }
+}
+
+/// It compresses the response body with the first schema accepted by the client
+pub fn compression(req: Request, next: Next) -> Response {
+    let compression_schemas = req.get_compression_schemas();
+
+    let mut res = next.run(req);
+
+    if res.get_body().is_empty() || res.get_headers().contains_key("Content-Encoding") {
+        return res;
+    }
+
+    // The body depends on the Accept-Encoding header, even when it is sent uncompressed,
+    // so caches must not serve it to clients that asked for another encoding
+    add_vary_accept_encoding(&mut res);
+
+    let Some(schema) = compression_schemas.first() else {
+        return res;
+    };
+
+    match schema.compress(res.get_body().to_vec()) {
+        Ok(compressed_body) => {
+            res.set_body(compressed_body);
+            res.headers_mut()
+                .insert("Content-Encoding", &schema.to_string());
+        }
+        // If there is an error on the compression process, the body is sent uncompressed
+        Err(err) => {
+            eprintln!("Error compressing the response: {}", err);
+        }
+    }
+
+    res
+}
+
+fn add_vary_accept_encoding(res: &mut Response) {
+    let already_varies = res
+        .get_headers()
+        .get_all("Vary")
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|field| field == "*" || field.eq_ignore_ascii_case("Accept-Encoding"));
+
+    if !already_varies {
+        res.headers_mut().append("Vary", "Accept-Encoding");
+    }
+}
@@ -1,121 +1,283 @@
-use std::{collections::HashMap, io::prelude::*, net::TcpStream};
+use std::{
+    collections::HashMap, io::prelude::*, net::TcpStream, str::FromStr, sync::Arc, time::Instant,
+};
 
-use crate::encoding::{CompressionSchema, CompressionSchemaError};
+use bytes::Bytes;
+
+use crate::{
+    encoding::{CompressionSchema, CompressionSchemaError},
+    header::{self, HeaderMap},
+    method::Method,
+    state::State,
+    url::{self, QueryParams},
+};
 
 const MAX_BYTES_STREAM_BUFFER: usize = 256;
+/// Room left in the request line for the method and the version besides the request target
+const MAX_REQUEST_LINE_OVERHEAD: usize = 64;
+const MAX_CHUNK_SIZE_LINE_LENGTH: usize = 1024;
+
+/// Maximum body size accepted by default when the server does not configure one (10 MiB)
+pub const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+pub const DEFAULT_MAX_URI_LENGTH: usize = 8 * 1024;
+pub const DEFAULT_MAX_HEADERS_SIZE: usize = 8 * 1024;
+
+/// Limits applied while parsing a request, so a client can not make the server buffer an
+/// unbounded amount of data.
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    pub max_uri_length: usize,
+    pub max_headers_size: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            max_headers_size: DEFAULT_MAX_HEADERS_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestParseError {
+    InvalidRequestLine,
+    InvalidHeader,
+    UnsupportedVersion,
+    UriTooLong,
+    HeadersTooLarge,
+    InvalidHost,
+    InvalidContentLength,
+    MissingContentLength,
+    /// Both `Transfer-Encoding` and `Content-Length` were sent
+    ConflictingBodyLength,
+    UnsupportedTransferEncoding,
+    /// `Expect` asks for something other than `100-continue`
+    UnsupportedExpectation,
+    InvalidChunk,
+    BodyTooLarge,
+    Timeout,
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for RequestParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestParseError::InvalidRequestLine => write!(f, "Invalid request line"),
+            RequestParseError::InvalidHeader => write!(f, "Invalid header"),
+            RequestParseError::UnsupportedVersion => write!(f, "Unsupported HTTP version"),
+            RequestParseError::UriTooLong => write!(f, "Request URI too long"),
+            RequestParseError::HeadersTooLarge => write!(f, "Request headers too large"),
+            RequestParseError::InvalidHost => write!(f, "Missing or repeated Host header"),
+            RequestParseError::InvalidContentLength => write!(f, "Invalid Content-Length header"),
+            RequestParseError::MissingContentLength => write!(f, "Missing Content-Length header"),
+            RequestParseError::ConflictingBodyLength => {
+                write!(f, "Both Transfer-Encoding and Content-Length headers")
+            }
+            RequestParseError::UnsupportedTransferEncoding => {
+                write!(f, "Unsupported Transfer-Encoding header")
+            }
+            RequestParseError::UnsupportedExpectation => {
+                write!(f, "Unsupported Expect header")
+            }
+            RequestParseError::InvalidChunk => write!(f, "Invalid chunked body"),
+            RequestParseError::BodyTooLarge => write!(f, "Request body too large"),
+            RequestParseError::Timeout => write!(f, "Timeout reading the request"),
+            RequestParseError::Io(err) => write!(f, "Error reading the request: {}", err),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Request {
-    pub method: String,
+    pub method: Method,
+    /// Request target exactly as it was sent, including the query string
+    pub target: String,
+    /// Percent-decoded path, without the query string
     pub path: String,
+    pub query: QueryParams,
     pub version: String,
-    pub headers: HashMap<String, String>,
+    pub headers: HeaderMap,
+    /// Trailer fields sent after a chunked body
+    pub trailers: HeaderMap,
     pub path_params: HashMap<String, String>,
-    pub body: String,
+    pub body: Bytes,
+    state: Arc<State>,
 }
 
 impl Request {
-    pub fn new(stream: &mut TcpStream) -> Self {
-        let mut bytes_received: Vec<u8> = vec![];
-        let mut buffer = [0u8; MAX_BYTES_STREAM_BUFFER];
-
-        loop {
-            let bytes_read = stream.read(&mut buffer).unwrap();
+    /// It parses the next request available in the connection. Bytes received after the end of
+    /// the request stay in the reader, so pipelined requests are parsed by the following calls.
+    pub fn new(
+        reader: &mut RequestReader,
+        limits: &RequestLimits,
+    ) -> Result<Self, RequestParseError> {
+        let request_line = reader
+            .read_until(b"\r\n", limits.max_uri_length + MAX_REQUEST_LINE_OVERHEAD)?
+            .ok_or(RequestParseError::UriTooLong)?;
 
-            bytes_received.extend_from_slice(&buffer[..bytes_read]);
+        let (method, target, version) =
+            parse_request_line(&String::from_utf8_lossy(&request_line))?;
 
-            if bytes_read < MAX_BYTES_STREAM_BUFFER {
-                break;
-            }
+        if target.len() > limits.max_uri_length {
+            return Err(RequestParseError::UriTooLong);
         }
 
-        let request_string = String::from_utf8_lossy(&bytes_received);
-        let (request_info, request_body) = request_string.split_once("\r\n\r\n").unwrap();
+        let headers = read_fields(reader, limits.max_headers_size)?;
 
-        let request_parts: Vec<String> = request_info.split("\r\n").map(String::from).collect();
+        // HTTP/1.1 clients must send exactly one Host header, HTTP/1.0 ones may omit it
+        let host_count = headers.get_all("Host").len();
 
-        let request_line: Vec<String> = request_parts
-            .first()
-            .unwrap_or(&String::new())
-            .split(" ")
-            .map(String::from)
-            .collect();
+        if host_count > 1 || (host_count == 0 && version == "HTTP/1.1") {
+            return Err(RequestParseError::InvalidHost);
+        }
 
-        let method = request_line.first().unwrap_or(&String::new()).to_string();
-        let path = request_line
-            .get(1)
-            .unwrap_or(&String::from("/"))
-            .to_string();
+        let transfer_encoding = headers.get("Transfer-Encoding");
+        let content_length = parse_content_length(&headers)?;
+        // HTTP/1.0 clients do not know about interim responses, so they never wait for one
+        let expects_continue = expects_continue(&headers)? && version == "HTTP/1.1";
 
-        let version = request_line.get(2).unwrap_or(&String::new()).to_string();
+        let (body_bytes, trailers) = match (transfer_encoding, content_length) {
+            // A proxy in front of the server could have used the other header to find where the
+            // body ends, so the request is rejected instead of preferring Transfer-Encoding
+            (Some(_), Some(_)) => return Err(RequestParseError::ConflictingBodyLength),
+            (Some(_), None) => {
+                if !is_only_chunked(&headers) {
+                    return Err(RequestParseError::UnsupportedTransferEncoding);
+                }
 
-        let request_headers: Option<Vec<String>> =
-            request_parts.get(1..).map(|parts| parts.to_vec());
+                if expects_continue {
+                    reader.write_continue()?;
+                }
 
-        let mut headers: HashMap<String, String> = HashMap::new();
+                read_chunked_body(reader, limits)?
+            }
+            (None, Some(content_length)) => {
+                if content_length > limits.max_body_size {
+                    return Err(RequestParseError::BodyTooLarge);
+                }
 
-        if let Some(request_headers) = request_headers {
-            for header in request_headers {
-                let (header_name, header_value) = header.split_once(":").unwrap();
+                if expects_continue && content_length > 0 {
+                    reader.write_continue()?;
+                }
 
-                headers.insert(header_name.to_string(), header_value.trim().to_string());
+                (reader.read_exact(content_length)?, HeaderMap::new())
             }
-        }
+            (None, None) if method_requires_body(&method) => {
+                return Err(RequestParseError::MissingContentLength);
+            }
+            (None, None) => (vec![], HeaderMap::new()),
+        };
 
-        Self {
+        let (raw_path, raw_query) = target.split_once('?').unwrap_or((target.as_str(), ""));
+        let path = url::percent_decode(raw_path);
+        let query = QueryParams::parse(raw_query);
+
+        Ok(Self {
             method,
+            target,
             path,
+            query,
             version,
             headers,
+            trailers,
             path_params: HashMap::new(),
-            body: String::from(request_body),
-        }
+            body: Bytes::from(body_bytes),
+            state: Arc::default(),
+        })
+    }
+
+    /// It returns the body as text, failing when it is not valid UTF-8
+    pub fn text(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.body)
     }
 
-    /// It gets the request path parameters from a handler path
-    pub fn set_path_params(&mut self, handler_path: &str) {
-        let pattern_values: Vec<&str> = handler_path.split('/').collect();
-        let path_values: Vec<&str> = self.path.split('/').collect();
+    /// It tells if the client wants to keep the connection open after this request. HTTP/1.1
+    /// connections are persistent unless `Connection: close` is sent, while HTTP/1.0 ones need
+    /// `Connection: keep-alive`.
+    pub fn is_keep_alive(&self) -> bool {
+        let has_connection_option = |option: &str| {
+            self.headers.get_all("Connection").iter().any(|value| {
+                value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case(option))
+            })
+        };
 
-        if pattern_values.len() != path_values.len() {
-            return;
+        match self.version.as_str() {
+            "HTTP/1.1" => !has_connection_option("close"),
+            _ => has_connection_option("keep-alive"),
         }
+    }
 
-        // It analyzes each fragment of the url and checks if it matches with the current pattern
-        for (index, pattern_value) in pattern_values.into_iter().enumerate() {
-            // Extracts the path parameters and insert it into the path_params map
-            if let Some(param_name) = pattern_value
-                .strip_prefix('{')
-                .and_then(|word| word.strip_suffix('}'))
-            {
-                self.path_params.insert(
-                    param_name.to_string(),
-                    path_values.get(index).unwrap().to_string(),
-                );
+    /// It returns the path parameter parsed as `T`, e.g. `req.param::<u64>("id")`. It is `None`
+    /// when the route has no such parameter or its value can not be parsed.
+    pub fn param<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.path_params.get(name)?.parse().ok()
+    }
 
-                continue;
-            }
+    /// It returns the host name the request is addressed to, taken from the `Host` header
+    /// without the port and in lowercase, e.g. `example.test` for `Example.test:8080`
+    pub fn host(&self) -> Option<String> {
+        let host = self.headers.get("Host")?.trim();
 
-            if pattern_value != *path_values.get(index).unwrap() {
-                // When pattern and path are differents, we clean the path_params variable as we do not need them
-                // anymore
-                self.path_params = HashMap::new();
+        // IPv6 addresses are enclosed in brackets, as their colons are not a port separator
+        let host_name = match host.strip_prefix('[') {
+            Some(address) => address
+                .split_once(']')
+                .map_or(host, |(address, _)| &host[..address.len() + 2]),
+            None => host
+                .split_once(':')
+                .map_or(host, |(host_name, _)| host_name),
+        };
 
-                return;
-            }
-        }
+        Some(host_name.trim_end_matches('.').to_ascii_lowercase())
+    }
+
+    /// It returns the application state of type `T` registered with `ServerHTTP::with_state`
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.state.get::<T>()
+    }
+
+    pub fn set_state(&mut self, state: Arc<State>) {
+        self.state = state;
+    }
+
+    /// It splits the path in segments decoding each of them on its own, so an encoded slash
+    /// (`%2F`) stays inside its segment instead of creating a new one
+    pub fn path_segments(&self) -> Vec<String> {
+        self.raw_path()
+            .split('/')
+            .map(url::percent_decode)
+            .collect()
+    }
+
+    /// It returns the path as it was sent, without decoding and without the query string
+    pub fn raw_path(&self) -> &str {
+        self.target
+            .split_once('?')
+            .map_or(self.target.as_str(), |(raw_path, _)| raw_path)
+    }
+
+    /// It replaces the path keeping the query string, e.g. to serve a normalized path
+    pub fn set_raw_path(&mut self, raw_path: &str) {
+        self.target = match self.target.split_once('?') {
+            Some((_, raw_query)) => format!("{}?{}", raw_path, raw_query),
+            None => raw_path.to_string(),
+        };
+        self.path = url::percent_decode(raw_path);
     }
 
     pub fn get_compression_schemas(&self) -> Vec<CompressionSchema> {
         let mut compression_schemas: Vec<CompressionSchema> = vec![];
-        let encoding = self.headers.get("Accept-Encoding");
-
-        if let Some(v) = encoding {
-            let schemas: Vec<String> = v.split(", ").map(|v| v.to_string()).collect();
 
-            for schema in schemas {
+        // The header may be sent several times, each of them with a list of schemas
+        for value in self.headers.get_all("Accept-Encoding") {
+            for schema in value.split(',') {
                 let compression_schema: Result<CompressionSchema, CompressionSchemaError> =
-                    schema.try_into();
+                    schema.trim().to_string().try_into();
 
                 if let Ok(cs) = compression_schema {
                     compression_schemas.push(cs);
@@ -126,3 +288,603 @@ impl Request {
         compression_schemas
     }
 }
+
+/// It wraps the client stream keeping the bytes that were received but not consumed yet, so
+/// requests can be parsed incrementally regardless of how they were split in TCP segments.
+#[derive(Debug)]
+pub struct RequestReader {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    deadline: Option<Instant>,
+}
+
+impl RequestReader {
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: vec![],
+            deadline: None,
+        }
+    }
+
+    /// It sets when reading must be finished, e.g. the whole request. Reads after it fail with
+    /// `RequestParseError::Timeout`, even if the client keeps sending bytes slowly. Without a
+    /// deadline only the read timeout of the stream applies.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// It waits until the client starts sending a new request. It returns `false` when the
+    /// connection was closed, and `RequestParseError::Timeout` when nothing was received before
+    /// the stream read timeout.
+    pub fn wait_for_request(&mut self) -> Result<bool, RequestParseError> {
+        if !self.buffer.is_empty() {
+            return Ok(true);
+        }
+
+        match self.fill_buffer() {
+            Ok(()) => Ok(true),
+            Err(RequestParseError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// It reads until `delimiter` is found and returns the bytes before it, or `None` when the
+    /// delimiter is not found within `limit` bytes. The delimiter is consumed but not returned.
+    fn read_until(
+        &mut self,
+        delimiter: &[u8],
+        limit: usize,
+    ) -> Result<Option<Vec<u8>>, RequestParseError> {
+        loop {
+            if let Some(position) = find_subsequence(&self.buffer, delimiter) {
+                if position > limit {
+                    return Ok(None);
+                }
+
+                let mut bytes: Vec<u8> = self.buffer.drain(..position + delimiter.len()).collect();
+
+                bytes.truncate(position);
+
+                return Ok(Some(bytes));
+            }
+
+            if self.buffer.len() > limit + delimiter.len() {
+                return Ok(None);
+            }
+
+            self.fill_buffer()?;
+        }
+    }
+
+    /// It tells the client to send the body it holds back after sending `Expect: 100-continue`.
+    /// Nothing is written when part of the body already arrived, as the client did not wait.
+    fn write_continue(&mut self) -> Result<(), RequestParseError> {
+        if !self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        self.stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .map_err(RequestParseError::Io)
+    }
+
+    fn read_exact(&mut self, length: usize) -> Result<Vec<u8>, RequestParseError> {
+        while self.buffer.len() < length {
+            self.fill_buffer()?;
+        }
+
+        Ok(self.buffer.drain(..length).collect())
+    }
+
+    /// It reads the next chunk of bytes from the stream, considering a closed connection as an
+    /// error because the request is not complete yet.
+    fn fill_buffer(&mut self) -> Result<(), RequestParseError> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                return Err(RequestParseError::Timeout);
+            }
+
+            // Each read waits at most until the deadline
+            self.stream
+                .set_read_timeout(Some(remaining))
+                .map_err(RequestParseError::Io)?;
+        }
+
+        let mut chunk = [0u8; MAX_BYTES_STREAM_BUFFER];
+        let bytes_read = self
+            .stream
+            .read(&mut chunk)
+            .map_err(|err| match err.kind() {
+                // Both kinds are returned depending on the platform when the read timeout expires
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                    RequestParseError::Timeout
+                }
+                _ => RequestParseError::Io(err),
+            })?;
+
+        if bytes_read == 0 {
+            return Err(RequestParseError::Io(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+
+        Ok(())
+    }
+}
+
+/// It decodes a body sent with `Transfer-Encoding: chunked`, returning the payload and the trailer
+/// fields sent after the last chunk.
+fn read_chunked_body(
+    reader: &mut RequestReader,
+    limits: &RequestLimits,
+) -> Result<(Vec<u8>, HeaderMap), RequestParseError> {
+    let mut body: Vec<u8> = vec![];
+
+    loop {
+        let line = reader
+            .read_until(b"\r\n", MAX_CHUNK_SIZE_LINE_LENGTH)?
+            .ok_or(RequestParseError::InvalidChunk)?;
+        let line = String::from_utf8_lossy(&line);
+
+        // Chunk extensions (e.g. "1a;name=value") have no meaning for us, so they are ignored
+        let size = line.split(';').next().unwrap_or_default().trim();
+
+        if size.is_empty() || !size.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(RequestParseError::InvalidChunk);
+        }
+
+        let size = usize::from_str_radix(size, 16).map_err(|_| RequestParseError::InvalidChunk)?;
+
+        if size == 0 {
+            break;
+        }
+
+        if body.len().saturating_add(size) > limits.max_body_size {
+            return Err(RequestParseError::BodyTooLarge);
+        }
+
+        body.extend(reader.read_exact(size)?);
+
+        if reader.read_exact(2)? != b"\r\n" {
+            return Err(RequestParseError::InvalidChunk);
+        }
+    }
+
+    let trailers = read_fields(reader, limits.max_headers_size)?;
+
+    Ok((body, trailers))
+}
+
+/// It parses a request line such as `GET /index.html HTTP/1.1` into method, target and version
+fn parse_request_line(line: &str) -> Result<(Method, String, String), RequestParseError> {
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    let [method, target, version] = parts.as_slice() else {
+        return Err(RequestParseError::InvalidRequestLine);
+    };
+
+    if method.is_empty() || !method.chars().all(is_token_char) || !is_valid_target(target) {
+        return Err(RequestParseError::InvalidRequestLine);
+    }
+
+    let version_number = version
+        .strip_prefix("HTTP/")
+        .ok_or(RequestParseError::InvalidRequestLine)?;
+
+    match version_number.as_bytes() {
+        b"1.0" | b"1.1" => {}
+        [major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            return Err(RequestParseError::UnsupportedVersion);
+        }
+        _ => return Err(RequestParseError::InvalidRequestLine),
+    }
+
+    Ok((
+        Method::from(*method),
+        target.to_string(),
+        version.to_string(),
+    ))
+}
+
+/// It reads header (or trailer) fields until the empty line that closes the section
+fn read_fields(
+    reader: &mut RequestReader,
+    max_size: usize,
+) -> Result<HeaderMap, RequestParseError> {
+    let mut fields = HeaderMap::new();
+    let mut remaining_size = max_size;
+
+    loop {
+        let line = reader
+            .read_until(b"\r\n", remaining_size)?
+            .ok_or(RequestParseError::HeadersTooLarge)?;
+
+        if line.is_empty() {
+            break;
+        }
+
+        remaining_size = remaining_size.saturating_sub(line.len() + 2);
+
+        let line = String::from_utf8_lossy(&line);
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(RequestParseError::InvalidHeader)?;
+
+        // Lines end with CRLF, but a bare CR or LF could still be inside the value and split it
+        // in two fields when a handler copies it into a response
+        if !header::is_valid_name(name) || !header::is_valid_value(value) {
+            return Err(RequestParseError::InvalidHeader);
+        }
+
+        fields.append(name, value.trim());
+    }
+
+    Ok(fields)
+}
+
+/// It returns the body length announced by the `Content-Length` headers. Repeated headers, or
+/// lists such as `3, 3`, are only accepted when every value is the same, otherwise the client and
+/// the server could disagree on where the body ends and a second request be smuggled in it.
+fn parse_content_length(headers: &HeaderMap) -> Result<Option<usize>, RequestParseError> {
+    let mut content_length = None;
+
+    for value in headers
+        .get_all("Content-Length")
+        .iter()
+        .flat_map(|value| value.split(','))
+    {
+        let value = value.trim();
+
+        // Only digits, `usize::from_str` would also accept a leading '+'
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(RequestParseError::InvalidContentLength);
+        }
+
+        let length = value
+            .parse::<usize>()
+            .map_err(|_| RequestParseError::InvalidContentLength)?;
+
+        if content_length.is_some_and(|content_length| content_length != length) {
+            return Err(RequestParseError::InvalidContentLength);
+        }
+
+        content_length = Some(length);
+    }
+
+    Ok(content_length)
+}
+
+/// It tells if the client waits for `100 Continue` before sending the body. It fails for any
+/// other expectation, as the server does not know how to meet it.
+fn expects_continue(headers: &HeaderMap) -> Result<bool, RequestParseError> {
+    let mut expects_continue = false;
+
+    for expectation in headers
+        .get_all("Expect")
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+    {
+        if !expectation.eq_ignore_ascii_case("100-continue") {
+            return Err(RequestParseError::UnsupportedExpectation);
+        }
+
+        expects_continue = true;
+    }
+
+    Ok(expects_continue)
+}
+
+/// Characters allowed in methods and header names (`tchar` in RFC 9110)
+pub(crate) fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// The target can not be empty nor contain spaces or control characters, which would end up in
+/// the `Location` of redirects and the logs
+fn is_valid_target(target: &str) -> bool {
+    !target.is_empty() && !target.chars().any(|c| c == ' ' || c.is_ascii_control())
+}
+
+/// Other codings such as gzip are not decoded, so the only one accepted is chunked on its own,
+/// otherwise the handler would get a body still encoded
+fn is_only_chunked(headers: &HeaderMap) -> bool {
+    let codings: Vec<&str> = headers
+        .get_all("Transfer-Encoding")
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    matches!(codings.as_slice(), [coding] if coding.eq_ignore_ascii_case("chunked"))
+}
+
+fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn method_requires_body(method: &Method) -> bool {
+    matches!(method, Method::Post | Method::Put | Method::Patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Shutdown, TcpListener},
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// It parses the raw bytes as sent by a client, returning the reader so the bytes left after
+    /// the request can be checked
+    fn parse(raw: &[u8]) -> (Result<Request, RequestParseError>, RequestReader) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        client.write_all(raw).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let mut reader = RequestReader::new(stream);
+        let result = Request::new(&mut reader, &RequestLimits::default());
+
+        (result, reader)
+    }
+
+    #[test]
+    fn it_times_out_a_request_sent_slowly_past_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        // Every byte arrives well before a per-read timeout would expire
+        let sender = std::thread::spawn(move || {
+            for byte in b"GET / HTTP/1.1\r\nHost: x\r\n" {
+                if client.write_all(&[*byte]).is_err() {
+                    return;
+                }
+
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let started_at = Instant::now();
+
+        let mut reader = RequestReader::new(stream);
+        reader.set_deadline(Some(started_at + Duration::from_millis(200)));
+
+        let result = Request::new(&mut reader, &RequestLimits::default());
+
+        assert!(matches!(result, Err(RequestParseError::Timeout)));
+        assert!(started_at.elapsed() < Duration::from_millis(400));
+
+        drop(reader);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn it_reads_the_body_with_content_length() {
+        let (result, reader) =
+            parse(b"POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nabcGET");
+
+        assert_eq!(result.unwrap().body.as_ref(), b"abc");
+        assert_eq!(reader.buffer, b"GET");
+    }
+
+    #[test]
+    fn it_accepts_repeated_content_length_with_the_same_value() {
+        let (result, _) = parse(
+            b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\nContent-Length: 3, 3\r\n\r\nabc",
+        );
+
+        assert_eq!(result.unwrap().body.as_ref(), b"abc");
+    }
+
+    #[test]
+    fn it_rejects_content_length_with_different_values() {
+        let (result, _) = parse(
+            b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\nContent-Length: 10\r\n\r\nabcGET /echo/smuggled HTTP/1.1\r\n\r\n",
+        );
+
+        assert!(matches!(
+            result,
+            Err(RequestParseError::InvalidContentLength)
+        ));
+
+        let (result, _) = parse(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 3, 10\r\n\r\nabc");
+
+        assert!(matches!(
+            result,
+            Err(RequestParseError::InvalidContentLength)
+        ));
+    }
+
+    #[test]
+    fn it_rejects_transfer_encoding_with_content_length() {
+        let (result, _) = parse(
+            b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 50\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /echo/smuggled HTTP/1.1\r\n\r\n",
+        );
+
+        assert!(matches!(
+            result,
+            Err(RequestParseError::ConflictingBodyLength)
+        ));
+    }
+
+    #[test]
+    fn it_decodes_a_chunked_body() {
+        let (result, reader) = parse(
+            b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\nX-Trailer: t\r\n\r\nGET",
+        );
+
+        let req = result.unwrap();
+
+        assert_eq!(req.body.as_ref(), b"abcde");
+        assert_eq!(req.trailers.get("X-Trailer"), Some("t"));
+        assert_eq!(reader.buffer, b"GET");
+    }
+
+    #[test]
+    fn it_rejects_transfer_codings_other_than_chunked() {
+        for transfer_encoding in [
+            "gzip, chunked",
+            "chunked, chunked",
+            "gzip\r\nTransfer-Encoding: chunked",
+            "chunked\r\nTransfer-Encoding: gzip",
+            "identity",
+        ] {
+            let raw = format!(
+                "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: {}\r\n\r\n0\r\n\r\n",
+                transfer_encoding
+            );
+
+            let (result, _) = parse(raw.as_bytes());
+
+            assert!(
+                matches!(result, Err(RequestParseError::UnsupportedTransferEncoding)),
+                "Transfer-Encoding: {:?}",
+                transfer_encoding
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_content_length_with_other_characters_than_digits() {
+        for content_length in ["+3", "-3", "3 3", "0x3", ""] {
+            let raw = format!(
+                "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\nabc",
+                content_length
+            );
+
+            let (result, _) = parse(raw.as_bytes());
+
+            assert!(
+                matches!(result, Err(RequestParseError::InvalidContentLength)),
+                "Content-Length: {:?}",
+                content_length
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_targets_with_control_characters() {
+        for target in [
+            "//a\nSet-Cookie:evil=1",
+            "/a\rb",
+            "/a\tb",
+            "/a\x7fb",
+            "/a\0b",
+        ] {
+            let raw = format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", target);
+
+            let (result, _) = parse(raw.as_bytes());
+
+            assert!(
+                matches!(result, Err(RequestParseError::InvalidRequestLine)),
+                "target: {:?}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_header_values_with_bare_cr_lf_or_nul() {
+        for value in ["a\nb", "a\rb", "a\0b", "a\nX-Injected: 1"] {
+            let raw = format!("GET / HTTP/1.1\r\nHost: x\r\nX-Foo: {}\r\n\r\n", value);
+
+            let (result, _) = parse(raw.as_bytes());
+
+            assert!(
+                matches!(result, Err(RequestParseError::InvalidHeader)),
+                "value: {:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_trailer_values_with_bare_lf() {
+        let (result, _) = parse(
+            b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX-Trailer: a\nb\r\n\r\n",
+        );
+
+        assert!(matches!(result, Err(RequestParseError::InvalidHeader)));
+    }
+
+    #[test]
+    fn it_sends_100_continue_before_reading_the_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // The body is only sent once the server asks for it, as curl does
+        let sender = std::thread::spawn(move || {
+            client
+                .write_all(b"PUT /files/a HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\n")
+                .unwrap();
+
+            let mut interim_response = [0u8; 25];
+            client.read_exact(&mut interim_response).unwrap();
+
+            client.write_all(b"abc").unwrap();
+
+            interim_response
+        });
+
+        let mut reader = RequestReader::new(stream);
+        let req = Request::new(&mut reader, &RequestLimits::default()).unwrap();
+
+        assert_eq!(req.body.as_ref(), b"abc");
+        assert_eq!(&sender.join().unwrap(), b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    #[test]
+    fn it_does_not_send_100_continue_for_a_body_too_large() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        client
+            .write_all(b"PUT /files/a HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 11\r\n\r\n")
+            .unwrap();
+
+        let limits = RequestLimits {
+            max_body_size: 10,
+            ..RequestLimits::default()
+        };
+        let result = Request::new(&mut RequestReader::new(stream), &limits);
+
+        assert!(matches!(result, Err(RequestParseError::BodyTooLarge)));
+
+        // The server side was dropped without writing anything
+        let mut received = vec![];
+        client.read_to_end(&mut received).unwrap();
+
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_expectations_other_than_100_continue() {
+        let (result, _) =
+            parse(b"PUT / HTTP/1.1\r\nHost: x\r\nExpect: 200-ok\r\nContent-Length: 3\r\n\r\nabc");
+
+        assert!(matches!(
+            result,
+            Err(RequestParseError::UnsupportedExpectation)
+        ));
+    }
+}
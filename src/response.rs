@@ -1,57 +1,38 @@
-use std::{collections::HashMap, io::Write, net::TcpStream, vec};
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+    vec,
+};
 
-use crate::encoding::CompressionSchema;
+use crate::header::{self, HeaderMap};
 
-#[derive(Debug, Clone)]
-pub enum StatusCode {
-    Ok,
-    NotFound,
-    BadRequest,
-    InternalServer,
-    Created,
-}
+pub use crate::status::StatusCode;
 
-impl std::fmt::Display for StatusCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            StatusCode::Ok => {
-                write!(f, "200 OK")
-            }
-            StatusCode::Created => {
-                write!(f, "201 Created")
-            }
-            StatusCode::NotFound => {
-                write!(f, "404 Not Found")
-            }
-            StatusCode::BadRequest => {
-                write!(f, "400 Bad Request")
-            }
-            StatusCode::InternalServer => {
-                write!(f, "500 Internal Server Error")
-            }
-        }
-    }
-}
+/// Place where a handler delivers its response when calling `send`, so the server can run the
+/// middlewares over it before writing it to the client.
+pub(crate) type ResponseSink = Arc<Mutex<Option<Response>>>;
 
 #[derive(Debug)]
-pub struct ResponseBuilder<'a> {
+pub struct ResponseBuilder {
     pub public_folder: Option<String>,
     version: String,
-    headers: HashMap<String, String>,
-    compression_schemas: Vec<CompressionSchema>,
-    stream: &'a mut TcpStream,
+    headers: HeaderMap,
     status_code: StatusCode,
     body: Vec<u8>,
 }
 
-impl<'a> ResponseBuilder<'a> {
-    pub fn new(stream: &'a mut TcpStream) -> Self {
+impl Default for ResponseBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseBuilder {
+    pub fn new() -> Self {
         Self {
             version: "HTTP/1.1".to_string(),
-            headers: HashMap::new(),
+            headers: HeaderMap::new(),
             public_folder: None,
-            compression_schemas: vec![],
-            stream,
             status_code: StatusCode::Ok,
             body: Vec::new(),
         }
@@ -68,38 +49,35 @@ impl<'a> ResponseBuilder<'a> {
         }
     }
 
-    pub fn with_compression_schemas(self, compression_schemas: Vec<CompressionSchema>) -> Self {
-        Self {
-            compression_schemas,
-            ..self
-        }
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+
+        self
     }
 
-    pub fn build(self) -> Response<'a> {
+    pub fn build(self) -> Response {
         Response {
             public_folder: self.public_folder,
             version: self.version,
             headers: self.headers,
-            compression_schemas: self.compression_schemas,
-            stream: self.stream,
             status_code: self.status_code,
             body: self.body,
+            sink: None,
         }
     }
 }
 
 #[derive(Debug)]
-pub struct Response<'a> {
+pub struct Response {
     pub public_folder: Option<String>,
     version: String,
-    headers: HashMap<String, String>,
-    compression_schemas: Vec<CompressionSchema>,
-    stream: &'a mut TcpStream,
+    headers: HeaderMap,
     status_code: StatusCode,
     body: Vec<u8>,
+    sink: Option<ResponseSink>,
 }
 
-impl Response<'_> {
+impl Response {
     pub fn status_code(self, status_code: StatusCode) -> Self {
         Self {
             status_code,
@@ -107,34 +85,116 @@ impl Response<'_> {
         }
     }
 
-    pub fn send_text(mut self, text: &str) {
-        self.headers
-            .insert("Content-Type".to_string(), "text/plain".to_string());
+    /// It sets a header, replacing any value already set with the same name
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+
+        self
+    }
+
+    /// It adds a header without replacing the values already set, e.g. to send several
+    /// `Set-Cookie` headers
+    pub fn append_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.append(name, value);
+
+        self
+    }
+
+    pub fn body(mut self, body: &[u8]) -> Self {
+        self.body = body.to_vec();
+
+        self
+    }
+
+    /// It sets a plain text body
+    pub fn text(mut self, text: &str) -> Self {
+        self.headers.insert("Content-Type", "text/plain");
         self.body = text.as_bytes().to_vec();
 
-        self.send();
+        self
     }
 
-    pub fn send_file(mut self, content: &str) {
-        self.headers.insert(
-            "Content-Type".to_string(),
-            "application/octet-stream".to_string(),
-        );
-        self.body = content.as_bytes().to_vec();
+    pub fn get_status_code(&self) -> &StatusCode {
+        &self.status_code
+    }
+
+    pub fn get_headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    pub fn get_body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+
+    pub fn send_text(self, text: &str) {
+        self.text(text).send();
+    }
+
+    pub fn send_file(mut self, content: &[u8]) {
+        self.headers
+            .insert("Content-Type", "application/octet-stream");
+        self.body = content.to_vec();
 
         self.send();
     }
 
+    /// It hands the response over to the server, which writes it to the client once the
+    /// middlewares have run
     pub fn send(mut self) {
-        let body = &self.get_body();
+        match self.sink.take() {
+            Some(sink) => {
+                let mut sent_response = sink.lock().unwrap_or_else(|err| err.into_inner());
 
-        if !self.compression_schemas.is_empty() {
-            self.insert_encoding_header();
+                *sent_response = Some(self);
+            }
+            None => {
+                eprintln!("Response sent without a pending request, it will be dropped");
+            }
+        }
+    }
+
+    /// It sends another response in place of this one, e.g. the one returned by a handler
+    pub(crate) fn send_instead(self, mut response: Response) {
+        response.sink = self.sink;
+        response.send();
+    }
+
+    pub(crate) fn with_sink(self, sink: ResponseSink) -> Self {
+        Self {
+            sink: Some(sink),
+            ..self
         }
+    }
 
-        if !body.is_empty() {
+    pub(crate) fn set_version(&mut self, version: &str) {
+        self.version = version.to_string();
+    }
+
+    /// It writes the response to the client. When `omit_body` is set only the head is written,
+    /// as expected for HEAD requests, but `Content-Length` still announces the body size.
+    ///
+    /// Nothing is written when a header name is not a token or a value has a CR, LF or NUL,
+    /// which could inject other headers, and an `InvalidData` error is returned instead.
+    pub(crate) fn write_to(
+        &mut self,
+        stream: &mut impl Write,
+        omit_body: bool,
+    ) -> std::io::Result<()> {
+        self.validate_headers()?;
+
+        // Content-Length is always sent when a body is allowed, otherwise the client could not
+        // tell where the body ends on a persistent connection
+        if self.status_code.allows_body() {
             self.headers
-                .insert("Content-Length".to_string(), body.len().to_string());
+                .insert("Content-Length", &self.body.len().to_string());
         }
 
         let headers_string = self.convert_headers_into_string();
@@ -144,42 +204,33 @@ impl Response<'_> {
             self.version, self.status_code, headers_string
         );
 
-        self.stream
-            .write_all(response.as_bytes())
-            .expect("Could not write a response");
+        stream.write_all(response.as_bytes())?;
 
-        if !self.body.is_empty() {
-            self.stream
-                .write_all(&self.get_body())
-                .expect("Could not write the body");
+        if !self.body.is_empty() && !omit_body && self.status_code.allows_body() {
+            stream.write_all(&self.body)?;
         }
-    }
 
-    fn get_body(&self) -> Vec<u8> {
-        if self.body.is_empty() || self.compression_schemas.is_empty() {
-            return self.body.clone();
-        }
+        stream.flush()
+    }
 
-        let schema = self.compression_schemas.first().unwrap();
+    fn validate_headers(&self) -> std::io::Result<()> {
+        for (name, value) in self.headers.iter() {
+            if !header::is_valid_name(name) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid response header name {:?}", name),
+                ));
+            }
 
-        match schema.compress(self.body.clone()) {
-            Ok(compressed_body) => compressed_body,
-            // If there is an error on the compression process, we return the body decompress
-            Err(_) => self.body.clone(),
+            if !header::is_valid_value(value) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid value of the response header {:?}", name),
+                ));
+            }
         }
-    }
-
-    fn insert_encoding_header(&mut self) {
-        let compression_schemas_str: Vec<String> = self
-            .compression_schemas
-            .iter()
-            .map(|v| v.to_string())
-            .collect();
 
-        self.headers.insert(
-            "Content-Encoding".to_string(),
-            compression_schemas_str.join(", "),
-        );
+        Ok(())
     }
 
     fn convert_headers_into_string(&self) -> String {
@@ -196,3 +247,132 @@ impl Response<'_> {
         format!("\r\n{}", headers_strings.join("\r\n"))
     }
 }
+
+/// Conversion into a response, used to answer with the values returned by handlers registered
+/// with `ServerHTTP::handle`, both on success and on error.
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+/// An empty response with the status code
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response {
+        ResponseBuilder::new().build().status_code(self)
+    }
+}
+
+/// A plain text response with the status code
+impl IntoResponse for (StatusCode, &str) {
+    fn into_response(self) -> Response {
+        ResponseBuilder::new()
+            .build()
+            .status_code(self.0)
+            .text(self.1)
+    }
+}
+
+impl IntoResponse for (StatusCode, String) {
+    fn into_response(self) -> Response {
+        (self.0, self.1.as_str()).into_response()
+    }
+}
+
+impl IntoResponse for &str {
+    fn into_response(self) -> Response {
+        ResponseBuilder::new().build().text(self)
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        self.as_str().into_response()
+    }
+}
+
+/// Unexpected errors are logged and answered with 500 Internal Server Error, without leaking
+/// their details to the client
+impl IntoResponse for anyhow::Error {
+    fn into_response(self) -> Response {
+        eprintln!("{:?}", self);
+
+        StatusCode::InternalServer.into_response()
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(mut res: Response) -> (std::io::Result<()>, String) {
+        let mut written: Vec<u8> = vec![];
+        let result = res.write_to(&mut written, false);
+
+        (result, String::from_utf8(written).unwrap())
+    }
+
+    #[test]
+    fn it_writes_the_headers_and_the_body() {
+        let res = ResponseBuilder::new()
+            .build()
+            .append_header("Set-Cookie", "a=1")
+            .append_header("Set-Cookie", "b=2")
+            .text("abc");
+
+        let (result, written) = write(res);
+
+        assert!(result.is_ok());
+        assert_eq!(
+            written,
+            "HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\nabc"
+        );
+    }
+
+    #[test]
+    fn it_does_not_write_header_values_with_line_breaks_or_nul() {
+        for value in ["a\r\nX-Injected: 1", "a\nb", "a\rb", "a\0b"] {
+            let res = ResponseBuilder::new().build().header("X-Echo", value);
+
+            let (result, written) = write(res);
+
+            assert_eq!(
+                result.unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData,
+                "value: {:?}",
+                value
+            );
+            assert!(written.is_empty());
+        }
+    }
+
+    #[test]
+    fn it_does_not_write_header_names_that_are_not_tokens() {
+        for name in ["", "X Echo", "X-Echo:", "X-Echo\r\nX-Injected"] {
+            let res = ResponseBuilder::new().build().header(name, "1");
+
+            let (result, written) = write(res);
+
+            assert_eq!(
+                result.unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData,
+                "name: {:?}",
+                name
+            );
+            assert!(written.is_empty());
+        }
+    }
+}
@@ -0,0 +1,927 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use regex_lite::Regex;
+
+use crate::{
+    handler::{self, HandlerPattern, Route},
+    method::Method,
+    middleware::{MiddlewareFn, Next},
+    request::Request,
+    response::{IntoResponse, Response},
+    url,
+};
+
+#[derive(Debug)]
+pub enum RouteError {
+    InvalidPattern { pattern: String, reason: String },
+    Conflict { existing: String, new: String },
+}
+
+impl std::fmt::Display for RouteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteError::InvalidPattern { pattern, reason } => {
+                write!(f, "Invalid route pattern {:?}, {}", pattern, reason)
+            }
+            RouteError::Conflict { existing, new } => {
+                write!(
+                    f,
+                    "Route {} conflicts with the registered {}",
+                    new, existing
+                )
+            }
+        }
+    }
+}
+
+/// Description of a registered route, e.g. to audit what a server serves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteInfo {
+    /// Virtual host serving the route, `None` for the default host
+    pub host: Option<String>,
+    pub method: Method,
+    /// Path pattern as it was registered, including the prefixes of nested routers
+    pub path: String,
+    pub name: Option<String>,
+    /// Middlewares that only run for this route, including the ones of its routers
+    pub middlewares: usize,
+}
+
+#[derive(Debug)]
+pub enum UrlError {
+    UnknownRoute(String),
+    MissingParam { route: String, param: String },
+}
+
+impl std::fmt::Display for UrlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UrlError::UnknownRoute(route) => write!(f, "There is no route named {:?}", route),
+            UrlError::MissingParam { route, param } => {
+                write!(
+                    f,
+                    "Missing parameter {:?} to build the URL of route {:?}",
+                    param, route
+                )
+            }
+        }
+    }
+}
+
+/// A group of routes built on its own, e.g. in the module of a feature, and mounted later on the
+/// server with `ServerHTTP::merge` or `ServerHTTP::nest`, or inside another router with `nest`.
+///
+/// Paths use the same syntax as the server ones. Conflicts are reported when the routes are
+/// mounted on the server.
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Vec<(HandlerPattern, Route)>,
+    middlewares: Vec<MiddlewareFn>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// It registers a handler and returns its route, like `ServerHTTP::handle_fn`
+    pub fn handle_fn<F>(&mut self, method: Method, path: &str, handler_fn: F) -> &mut Route
+    where
+        F: Fn(Request, Response) + Send + Sync + 'static,
+    {
+        let handler_pattern = HandlerPattern(method, path.to_string());
+
+        self.add_route(handler_pattern, Route::new(Arc::new(handler_fn)))
+    }
+
+    /// It registers a handler that returns its response, like `ServerHTTP::handle`
+    pub fn handle<F, E>(&mut self, method: Method, path: &str, handler: F) -> &mut Route
+    where
+        F: Fn(Request) -> Result<Response, E> + Send + Sync + 'static,
+        E: IntoResponse,
+    {
+        let handler_pattern = HandlerPattern(method, path.to_string());
+
+        self.add_route(
+            handler_pattern,
+            Route::new(handler::returning_handler(handler)),
+        )
+    }
+
+    /// It adds a middleware that runs for every route of the router, including the nested ones,
+    /// e.g. to require authentication on a whole group. It only runs for requests matching one
+    /// of those routes, after the server middlewares and before the route ones.
+    pub fn use_middleware<F>(&mut self, middleware: F) -> &mut Self
+    where
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static,
+    {
+        self.middlewares.push(Arc::new(middleware));
+
+        self
+    }
+
+    /// It adds the routes of another router under the prefix, e.g. `/users/{id}` nested in `/api`
+    /// becomes `/api/users/{id}`. The middlewares of that router keep applying only to its routes.
+    pub fn nest(&mut self, prefix: &str, router: Router) -> &mut Self {
+        let prefix = prefix.trim_end_matches('/');
+
+        for (HandlerPattern(method, path), route) in router.into_routes() {
+            let path = match path.as_str() {
+                // The root of the nested router is the prefix itself, e.g. `/api` instead of `/api/`
+                "/" if !prefix.is_empty() => prefix.to_string(),
+                _ => format!("{}{}", prefix, path),
+            };
+
+            self.routes.push((HandlerPattern(method, path), route));
+        }
+
+        self
+    }
+
+    /// It adds the routes of another router without any prefix
+    pub fn merge(&mut self, router: Router) -> &mut Self {
+        self.nest("", router)
+    }
+
+    fn add_route(&mut self, handler_pattern: HandlerPattern, route: Route) -> &mut Route {
+        self.routes.push((handler_pattern, route));
+
+        let (_, route) = self.routes.last_mut().expect("The route was just added");
+
+        route
+    }
+
+    /// It returns the routes with the router middlewares placed before their own ones
+    pub(crate) fn into_routes(self) -> Vec<(HandlerPattern, Route)> {
+        let middlewares = self.middlewares;
+
+        self.routes
+            .into_iter()
+            .map(|(handler_pattern, mut route)| {
+                route.middlewares.splice(0..0, middlewares.iter().cloned());
+
+                (handler_pattern, route)
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for Router {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Router")
+            .field("routes", &self.routes)
+            .field("middlewares", &self.middlewares.len())
+            .finish()
+    }
+}
+
+/// Routes organized as a tree of path segments, so a request is matched by walking its segments
+/// once instead of testing every registered route. When several routes match the same path, static
+/// segments win over parameters and parameters over catch-all segments, e.g. `/files/index` is
+/// preferred over `/files/{filename}`, which is preferred over `/files/{*path}`.
+///
+/// Besides `{name}`, segments can be:
+/// - `{name:u64}` or `{name:[a-z-]+}`: a parameter that only matches values of the type or the
+///   regular expression, otherwise the request falls through to the other routes.
+/// - `{name?}` or `{name?:u64}`: an optional parameter, only allowed at the end of the path.
+/// - `{*name}`: a catch-all parameter with the rest of the path, e.g. `css/site.css`. It must be
+///   the last segment.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RouteTree {
+    root: Node,
+    routes: Vec<(HandlerPattern, Route)>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    static_children: HashMap<String, Node>,
+    /// Constrained parameters come first, so they are tried before the unconstrained one
+    param_children: Vec<ParamChild>,
+    catch_all_child: Option<(String, Box<Node>)>,
+    /// Index of the route for each method in `RouteTree::routes`
+    routes: BTreeMap<Method, usize>,
+}
+
+#[derive(Debug, Clone)]
+struct ParamChild {
+    name: String,
+    constraint: Option<ParamConstraint>,
+    node: Node,
+}
+
+#[derive(Debug, Clone)]
+struct ParamConstraint {
+    spec: String,
+    matcher: Matcher,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Type(fn(&str) -> bool),
+    Pattern(Regex),
+}
+
+impl ParamConstraint {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let matcher = match spec {
+            "u8" => Matcher::Type(|value| value.parse::<u8>().is_ok()),
+            "u16" => Matcher::Type(|value| value.parse::<u16>().is_ok()),
+            "u32" => Matcher::Type(|value| value.parse::<u32>().is_ok()),
+            "u64" => Matcher::Type(|value| value.parse::<u64>().is_ok()),
+            "usize" => Matcher::Type(|value| value.parse::<usize>().is_ok()),
+            "i8" => Matcher::Type(|value| value.parse::<i8>().is_ok()),
+            "i16" => Matcher::Type(|value| value.parse::<i16>().is_ok()),
+            "i32" => Matcher::Type(|value| value.parse::<i32>().is_ok()),
+            "i64" => Matcher::Type(|value| value.parse::<i64>().is_ok()),
+            "isize" => Matcher::Type(|value| value.parse::<isize>().is_ok()),
+            "f32" => Matcher::Type(|value| value.parse::<f32>().is_ok()),
+            "f64" => Matcher::Type(|value| value.parse::<f64>().is_ok()),
+            "bool" => Matcher::Type(|value| value.parse::<bool>().is_ok()),
+            // Anything else is a regular expression that must match the whole value
+            _ => Regex::new(&format!("^(?:{})$", spec))
+                .map(Matcher::Pattern)
+                .map_err(|err| format!("constraint {:?} is not valid: {}", spec, err))?,
+        };
+
+        Ok(Self {
+            spec: spec.to_string(),
+            matcher,
+        })
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match &self.matcher {
+            Matcher::Type(is_valid) => is_valid(value),
+            Matcher::Pattern(regex) => regex.is_match(value),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Segment {
+    Static(String),
+    Param {
+        name: String,
+        constraint: Option<ParamConstraint>,
+    },
+    CatchAll(String),
+}
+
+impl RouteTree {
+    pub fn insert(
+        &mut self,
+        pattern: HandlerPattern,
+        route: Route,
+    ) -> Result<&mut Route, RouteError> {
+        let method = pattern.get_method();
+        let variants = parse_pattern(&pattern.get_path())?;
+
+        // Every variant is checked first, so a conflict does not leave the route half registered
+        for segments in &variants {
+            let node = self.root.child_mut(segments, &pattern)?;
+
+            if let Some(&index) = node.routes.get(&method) {
+                let (existing, _) = &self.routes[index];
+
+                return Err(RouteError::Conflict {
+                    existing: format!("{} {}", existing.get_method(), existing.get_path()),
+                    new: format!("{} {}", method, pattern.get_path()),
+                });
+            }
+        }
+
+        let index = self.routes.len();
+
+        for segments in &variants {
+            self.root
+                .child_mut(segments, &pattern)?
+                .routes
+                .insert(method.clone(), index);
+        }
+
+        self.routes.push((pattern, route));
+
+        Ok(&mut self.routes[index].1)
+    }
+
+    /// It returns the route for the method with the highest precedence among the ones matching
+    /// the path segments, together with its parameters. Segments are expected without the
+    /// leading empty one, e.g. `/echo/abc` is `["echo", "abc"]`.
+    ///
+    /// Branches are walked in precedence order and the search stops at the first route found,
+    /// backtracking only when a branch has no route for the method, so a request usually walks
+    /// its segments once.
+    pub fn find_route<'a>(
+        &'a self,
+        segments: &[String],
+        method: &Method,
+    ) -> Option<(&'a Route, HashMap<String, String>)> {
+        let mut params = HashMap::new();
+
+        let node = self
+            .root
+            .find_first(segments, &mut params, &|node: &Node| {
+                node.routes.contains_key(method)
+            })?;
+
+        let (_, route) = &self.routes[node.routes[method]];
+
+        Some((route, params))
+    }
+
+    /// It tells if any route matches the path segments, whatever its method
+    pub fn has_match(&self, segments: &[String]) -> bool {
+        self.root
+            .find_first(segments, &mut HashMap::new(), &|node: &Node| {
+                !node.routes.is_empty()
+            })
+            .is_some()
+    }
+
+    /// It returns the methods of every route matching the path segments. It walks every branch,
+    /// so it is only meant for requests no route answers, e.g. to tell the methods allowed for
+    /// the path.
+    pub fn matching_methods(&self, segments: &[String]) -> BTreeSet<Method> {
+        let mut methods = BTreeSet::new();
+
+        self.root.collect_methods(segments, &mut methods);
+
+        methods
+    }
+
+    /// It builds the path of the route with the name, filling its parameters with the values.
+    /// Values are percent-encoded, except the slashes of catch-all parameters, and optional
+    /// parameters without a value are left out. Values for unknown parameters are ignored.
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        let (pattern, _) = self
+            .routes
+            .iter()
+            .find(|(_, route)| route.name.as_deref() == Some(name))
+            .ok_or_else(|| UrlError::UnknownRoute(name.to_string()))?;
+
+        let get_param = |param: &str| {
+            params
+                .iter()
+                .find(|(key, _)| *key == param)
+                .map(|(_, value)| *value)
+        };
+
+        let mut path = String::new();
+
+        for segment in pattern.get_path().split('/').skip(1) {
+            let Some(param) = segment
+                .strip_prefix('{')
+                .and_then(|word| word.strip_suffix('}'))
+            else {
+                path.push('/');
+                path.push_str(segment);
+                continue;
+            };
+
+            let (param, is_catch_all) = match param.strip_prefix('*') {
+                Some(param) => (param, true),
+                None => (param.split(':').next().unwrap_or(param), false),
+            };
+
+            let (param, is_optional) = match param.strip_suffix('?') {
+                Some(param) => (param, true),
+                None => (param, false),
+            };
+
+            let value = match get_param(param) {
+                Some(value) => value,
+                None if is_optional => continue,
+                None => {
+                    return Err(UrlError::MissingParam {
+                        route: name.to_string(),
+                        param: param.to_string(),
+                    })
+                }
+            };
+
+            path.push('/');
+
+            if is_catch_all {
+                let encoded_segments: Vec<String> =
+                    value.split('/').map(url::percent_encode).collect();
+
+                path.push_str(&encoded_segments.join("/"));
+            } else {
+                path.push_str(&url::percent_encode(value));
+            }
+        }
+
+        // Without its optional parameters `/{page?}` is the root path
+        if path.is_empty() {
+            path.push('/');
+        }
+
+        Ok(path)
+    }
+
+    /// It returns the names used by more than one route
+    pub fn duplicated_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .routes
+            .iter()
+            .filter_map(|(_, route)| route.name.as_deref())
+            .collect();
+
+        names.sort_unstable();
+
+        let mut duplicated_names: Vec<&str> = names
+            .windows(2)
+            .filter(|pair| pair[0] == pair[1])
+            .map(|pair| pair[0])
+            .collect();
+
+        duplicated_names.dedup();
+
+        duplicated_names
+    }
+
+    /// It iterates over every registered route, in registration order
+    pub fn routes(&self) -> Vec<&(HandlerPattern, Route)> {
+        self.routes.iter().collect()
+    }
+}
+
+impl Node {
+    /// It returns the node for the segments, creating the missing ones
+    fn child_mut(
+        &mut self,
+        segments: &[Segment],
+        pattern: &HandlerPattern,
+    ) -> Result<&mut Node, RouteError> {
+        let Some((segment, rest)) = segments.split_first() else {
+            return Ok(self);
+        };
+
+        let conflict = |existing_name: &str| RouteError::Conflict {
+            existing: format!("parameter {{{}}}", existing_name),
+            new: format!("{} {}", pattern.get_method(), pattern.get_path()),
+        };
+
+        let child = match segment {
+            Segment::Static(segment) => self.static_children.entry(segment.clone()).or_default(),
+            Segment::Param { name, constraint } => {
+                let spec = constraint.as_ref().map(|constraint| &constraint.spec);
+
+                let position = self.param_children.iter().position(|child| {
+                    child.constraint.as_ref().map(|constraint| &constraint.spec) == spec
+                });
+
+                let position = match position {
+                    // The same parameter can not be captured with different names, otherwise
+                    // the value would be stored under a name the handler does not expect
+                    Some(position) if self.param_children[position].name != *name => {
+                        return Err(conflict(&self.param_children[position].name));
+                    }
+                    Some(position) => position,
+                    None => {
+                        let child = ParamChild {
+                            name: name.clone(),
+                            constraint: constraint.clone(),
+                            node: Node::default(),
+                        };
+
+                        // The unconstrained parameter is kept last, as it matches any value
+                        let position = match constraint {
+                            Some(_) => self
+                                .param_children
+                                .iter()
+                                .position(|child| child.constraint.is_none())
+                                .unwrap_or(self.param_children.len()),
+                            None => self.param_children.len(),
+                        };
+
+                        self.param_children.insert(position, child);
+
+                        position
+                    }
+                };
+
+                &mut self.param_children[position].node
+            }
+            Segment::CatchAll(name) => {
+                let (existing_name, child) = self
+                    .catch_all_child
+                    .get_or_insert_with(|| (name.clone(), Box::default()));
+
+                if existing_name != name {
+                    return Err(conflict(existing_name));
+                }
+
+                child
+            }
+        };
+
+        child.child_mut(rest, pattern)
+    }
+
+    /// It returns the first node for which `accepts` holds, trying static segments, then
+    /// parameters and then the catch-all parameter. The parameters of the node found are left in
+    /// `params`.
+    fn find_first<'a>(
+        &'a self,
+        segments: &[String],
+        params: &mut HashMap<String, String>,
+        accepts: &dyn Fn(&Node) -> bool,
+    ) -> Option<&'a Node> {
+        let Some((segment, rest)) = segments.split_first() else {
+            return accepts(self).then_some(self);
+        };
+
+        if let Some(child) = self.static_children.get(segment) {
+            if let Some(node) = child.find_first(rest, params, accepts) {
+                return Some(node);
+            }
+        }
+
+        for child in &self.param_children {
+            let is_valid = child
+                .constraint
+                .as_ref()
+                .map_or(true, |constraint| constraint.matches(segment));
+
+            if !is_valid {
+                continue;
+            }
+
+            let previous = params.insert(child.name.clone(), segment.clone());
+
+            if let Some(node) = child.node.find_first(rest, params, accepts) {
+                return Some(node);
+            }
+
+            restore_param(params, &child.name, previous);
+        }
+
+        if let Some((name, child)) = &self.catch_all_child {
+            if accepts(child) {
+                params.insert(name.clone(), segments.join("/"));
+
+                return Some(child);
+            }
+        }
+
+        None
+    }
+
+    fn collect_methods(&self, segments: &[String], methods: &mut BTreeSet<Method>) {
+        let Some((segment, rest)) = segments.split_first() else {
+            methods.extend(self.routes.keys().cloned());
+
+            return;
+        };
+
+        if let Some(child) = self.static_children.get(segment) {
+            child.collect_methods(rest, methods);
+        }
+
+        for child in &self.param_children {
+            let is_valid = child
+                .constraint
+                .as_ref()
+                .map_or(true, |constraint| constraint.matches(segment));
+
+            if is_valid {
+                child.node.collect_methods(rest, methods);
+            }
+        }
+
+        if let Some((_, child)) = &self.catch_all_child {
+            methods.extend(child.routes.keys().cloned());
+        }
+    }
+}
+
+fn restore_param(params: &mut HashMap<String, String>, name: &str, previous: Option<String>) {
+    match previous {
+        Some(value) => params.insert(name.to_string(), value),
+        None => params.remove(name),
+    };
+}
+
+/// It parses the path of a route pattern into the segments of each path it matches. There is
+/// more than one when it ends with optional parameters, e.g. `/posts/{page?}` is both `/posts`
+/// and `/posts/{page}`.
+fn parse_pattern(path: &str) -> Result<Vec<Vec<Segment>>, RouteError> {
+    let invalid = |reason: &str| RouteError::InvalidPattern {
+        pattern: path.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let Some(relative_path) = path.strip_prefix('/') else {
+        return Err(invalid("it must start with '/'"));
+    };
+
+    let raw_segments: Vec<&str> = relative_path.split('/').collect();
+
+    let mut segments = vec![];
+    let mut required_segments = None;
+
+    for (position, raw_segment) in raw_segments.iter().enumerate() {
+        let is_last = position == raw_segments.len() - 1;
+
+        let Some(param) = raw_segment
+            .strip_prefix('{')
+            .and_then(|word| word.strip_suffix('}'))
+        else {
+            if required_segments.is_some() {
+                return Err(invalid("optional parameters must be at the end"));
+            }
+
+            segments.push(Segment::Static(raw_segment.to_string()));
+            continue;
+        };
+
+        if let Some(name) = param.strip_prefix('*') {
+            if !is_last {
+                return Err(invalid("catch-all parameters must be the last segment"));
+            }
+
+            if required_segments.is_some() {
+                return Err(invalid("optional parameters must be at the end"));
+            }
+
+            if name.is_empty() {
+                return Err(invalid("parameters need a name"));
+            }
+
+            segments.push(Segment::CatchAll(name.to_string()));
+            continue;
+        }
+
+        let (name, constraint) = match param.split_once(':') {
+            Some((name, spec)) => (
+                name,
+                Some(ParamConstraint::parse(spec).map_err(|reason| invalid(&reason))?),
+            ),
+            None => (param, None),
+        };
+
+        let (name, is_optional) = match name.strip_suffix('?') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+
+        if name.is_empty() {
+            return Err(invalid("parameters need a name"));
+        }
+
+        if is_optional {
+            required_segments.get_or_insert(segments.len());
+        } else if required_segments.is_some() {
+            return Err(invalid("optional parameters must be at the end"));
+        }
+
+        segments.push(Segment::Param {
+            name: name.to_string(),
+            constraint,
+        });
+    }
+
+    let required_segments = required_segments.unwrap_or(segments.len());
+
+    // One variant per number of optional parameters present, sharing the required segments
+    let variants = (required_segments..=segments.len())
+        .map(|length| match length {
+            // Without the optional parameters `/{page?}` is the root path
+            0 => vec![Segment::Static(String::new())],
+            _ => segments[..length].to_vec(),
+        })
+        .collect();
+
+    Ok(variants)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(patterns: &[(Method, &str)]) -> RouteTree {
+        let mut tree = RouteTree::default();
+
+        for (method, path) in patterns {
+            tree.insert(
+                HandlerPattern(method.clone(), path.to_string()),
+                route(path),
+            )
+            .unwrap();
+        }
+
+        tree
+    }
+
+    /// A route named after its pattern, so tests can tell which one was found
+    fn route(path: &str) -> Route {
+        let mut route = Route::new(Arc::new(|_, _| {}));
+        route.with_name(path);
+
+        route
+    }
+
+    fn segments(path: &str) -> Vec<String> {
+        path.split('/').skip(1).map(String::from).collect()
+    }
+
+    /// It returns the name of the route found and its parameters sorted by name
+    fn find(
+        tree: &RouteTree,
+        method: Method,
+        path: &str,
+    ) -> Option<(String, Vec<(String, String)>)> {
+        let (route, params) = tree.find_route(&segments(path), &method)?;
+
+        let mut params: Vec<(String, String)> = params.into_iter().collect();
+        params.sort();
+
+        Some((route.name.clone().unwrap(), params))
+    }
+
+    fn params(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn it_prefers_static_segments_then_params_then_catch_all() {
+        let tree = tree(&[
+            (Method::Get, "/files/index"),
+            (Method::Get, "/files/{name}"),
+            (Method::Get, "/files/{*path}"),
+        ]);
+
+        assert_eq!(
+            find(&tree, Method::Get, "/files/index"),
+            Some(("/files/index".to_string(), vec![]))
+        );
+        assert_eq!(
+            find(&tree, Method::Get, "/files/a.txt"),
+            Some(("/files/{name}".to_string(), params(&[("name", "a.txt")])))
+        );
+        assert_eq!(
+            find(&tree, Method::Get, "/files/css/site.css"),
+            Some((
+                "/files/{*path}".to_string(),
+                params(&[("path", "css/site.css")])
+            ))
+        );
+    }
+
+    #[test]
+    fn it_backtracks_when_a_branch_has_no_route() {
+        let tree = tree(&[(Method::Get, "/a/b/d"), (Method::Get, "/a/{x}/c")]);
+
+        assert_eq!(
+            find(&tree, Method::Get, "/a/b/c"),
+            Some(("/a/{x}/c".to_string(), params(&[("x", "b")])))
+        );
+        assert_eq!(find(&tree, Method::Get, "/a/b/e"), None);
+    }
+
+    #[test]
+    fn it_backtracks_when_a_branch_has_no_route_for_the_method() {
+        let tree = tree(&[(Method::Post, "/items/new"), (Method::Get, "/items/{id}")]);
+
+        assert_eq!(
+            find(&tree, Method::Get, "/items/new"),
+            Some(("/items/{id}".to_string(), params(&[("id", "new")])))
+        );
+        assert_eq!(
+            find(&tree, Method::Post, "/items/new"),
+            Some(("/items/new".to_string(), vec![]))
+        );
+        assert_eq!(find(&tree, Method::Delete, "/items/new"), None);
+    }
+
+    #[test]
+    fn it_falls_through_params_whose_constraint_does_not_match() {
+        let tree = tree(&[
+            (Method::Get, "/users/{id:u64}"),
+            (Method::Get, "/users/{slug:[a-z-]+}"),
+            (Method::Get, "/users/{name}"),
+        ]);
+
+        assert_eq!(
+            find(&tree, Method::Get, "/users/42"),
+            Some(("/users/{id:u64}".to_string(), params(&[("id", "42")])))
+        );
+        assert_eq!(
+            find(&tree, Method::Get, "/users/jane-doe"),
+            Some((
+                "/users/{slug:[a-z-]+}".to_string(),
+                params(&[("slug", "jane-doe")])
+            ))
+        );
+        assert_eq!(
+            find(&tree, Method::Get, "/users/Jane"),
+            Some(("/users/{name}".to_string(), params(&[("name", "Jane")])))
+        );
+    }
+
+    #[test]
+    fn it_matches_optional_params_with_and_without_value() {
+        let tree = tree(&[(Method::Get, "/posts/{page?:u64}")]);
+
+        assert_eq!(
+            find(&tree, Method::Get, "/posts"),
+            Some(("/posts/{page?:u64}".to_string(), vec![]))
+        );
+        assert_eq!(
+            find(&tree, Method::Get, "/posts/2"),
+            Some(("/posts/{page?:u64}".to_string(), params(&[("page", "2")])))
+        );
+        assert_eq!(find(&tree, Method::Get, "/posts/abc"), None);
+    }
+
+    #[test]
+    fn it_matches_catch_all_params_with_the_rest_of_the_path() {
+        let tree = tree(&[(Method::Get, "/static/{*path}")]);
+
+        assert_eq!(
+            find(&tree, Method::Get, "/static/css/site.css"),
+            Some((
+                "/static/{*path}".to_string(),
+                params(&[("path", "css/site.css")])
+            ))
+        );
+        assert_eq!(
+            find(&tree, Method::Get, "/static/"),
+            Some(("/static/{*path}".to_string(), params(&[("path", "")])))
+        );
+        assert_eq!(find(&tree, Method::Get, "/static"), None);
+    }
+
+    #[test]
+    fn it_collects_the_methods_of_every_matching_route() {
+        let tree = tree(&[
+            (Method::Get, "/items/new"),
+            (Method::Post, "/items/{id}"),
+            (Method::Delete, "/items/{*path}"),
+            (Method::Put, "/other"),
+        ]);
+
+        assert_eq!(
+            tree.matching_methods(&segments("/items/new")),
+            BTreeSet::from([Method::Get, Method::Post, Method::Delete])
+        );
+        assert!(tree.has_match(&segments("/items/3")));
+        assert!(!tree.has_match(&segments("/missing")));
+    }
+
+    #[test]
+    fn it_rejects_conflicting_routes() {
+        let mut tree = tree(&[
+            (Method::Get, "/items/{id}"),
+            (Method::Get, "/files/{*path}"),
+        ]);
+
+        for (method, path) in [
+            (Method::Get, "/items/{id}"),
+            (Method::Get, "/items/{name}"),
+            (Method::Get, "/files/{*rest}"),
+        ] {
+            let result = tree.insert(HandlerPattern(method, path.to_string()), route(path));
+
+            assert!(
+                matches!(result, Err(RouteError::Conflict { .. })),
+                "{}",
+                path
+            );
+        }
+
+        // The same pattern with another method is not a conflict
+        assert!(tree
+            .insert(
+                HandlerPattern(Method::Post, "/items/{id}".to_string()),
+                route("/items/{id}")
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn it_rejects_invalid_patterns() {
+        for path in [
+            "items",
+            "/files/{*path}/edit",
+            "/posts/{page?}/edit",
+            "/users/{id:[}",
+        ] {
+            let mut tree = RouteTree::default();
+            let result = tree.insert(HandlerPattern(Method::Get, path.to_string()), route(path));
+
+            assert!(
+                matches!(result, Err(RouteError::InvalidPattern { .. })),
+                "{}",
+                path
+            );
+        }
+    }
+}
@@ -1,73 +1,1496 @@
-use std::{collections::HashMap, net::TcpListener};
+use std::{
+    collections::BTreeSet,
+    io,
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    handler::{HandlerFn, HandlerPattern},
-    request::Request,
-    response::{Response, ResponseBuilder, StatusCode},
+    handler::{HandlerFn, Route},
+    method::Method,
+    middleware::{self, MiddlewareFn, Next},
+    request::{Request, RequestLimits, RequestParseError, RequestReader},
+    response::{IntoResponse, Response, ResponseBuilder, ResponseSink, StatusCode},
+    router::{RouteError, RouteInfo, Router, UrlError},
+    shutdown::{ConnectionGuard, ShutdownHandle},
+    state::State,
+    url,
+    virtual_host::VirtualHost,
+    worker_pool::WorkerPool,
 };
 
-#[derive(Debug, Default)]
+/// Time a client has to send a complete request before receiving 408 Request Timeout
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time a client has to read a whole response, so one that stops reading does not hold a worker
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time an idle persistent connection is kept open waiting for the next request
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+/// Each worker serves one connection at a time, including the idle time of persistent ones
+const DEFAULT_WORKERS: usize = 64;
+/// Accepted connections waiting for a free worker before the server is considered overloaded
+const DEFAULT_QUEUE_DEPTH: usize = 128;
+/// Time in-flight requests get to finish when the server shuts down
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often idle connections check if the server is shutting down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Time an overloaded server gets to write the 503 response, so a slow client can not block
+/// the accept loop
+const OVERLOAD_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Pause after failing to accept a connection, e.g. when the process ran out of file
+/// descriptors, so the accept loop does not spin until some are released
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Errors starting the server. Errors serving a connection only affect that connection, so they
+/// are logged instead.
+#[derive(Debug)]
+pub enum ServerError {
+    Bind(io::Error),
+    /// Route names must be unique for `url_for` to know which route to build
+    DuplicatedRouteName(String),
+    NoListeners,
+    Io(io::Error),
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::Bind(err) => write!(f, "Error binding the address: {}", err),
+            ServerError::DuplicatedRouteName(name) => {
+                write!(f, "Route name {:?} is used by more than one route", name)
+            }
+            ServerError::NoListeners => write!(f, "No address to listen on"),
+            ServerError::Io(err) => write!(f, "Error starting the server: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Bind(err) | ServerError::Io(err) => Some(err),
+            ServerError::DuplicatedRouteName(_) | ServerError::NoListeners => None,
+        }
+    }
+}
+
 pub struct ServerHTTP {
-    handlers: HashMap<HandlerPattern, HandlerFn>,
-    public_folder: Option<String>,
+    default_host: VirtualHost,
+    virtual_hosts: Vec<VirtualHost>,
+    middlewares: Vec<MiddlewareFn>,
+    group_middlewares: Vec<(String, MiddlewareFn)>,
+    compression: bool,
+    state: Arc<State>,
+    path_normalization: PathNormalization,
+    connection_options: ConnectionOptions,
+    pool_options: PoolOptions,
+    drain_timeout: Duration,
+}
+
+/// Everything needed to answer a request, shared by every connection thread
+struct Application {
+    default_host: VirtualHost,
+    /// Sorted from the most specific host to the least one
+    virtual_hosts: Vec<VirtualHost>,
+    middlewares: Vec<MiddlewareFn>,
+    group_middlewares: Vec<(String, MiddlewareFn)>,
+    state: Arc<State>,
+    path_normalization: PathNormalization,
+    shutdown: ShutdownHandle,
+}
+
+/// How request paths are cleaned up before looking for their handler. Global middlewares see
+/// the path as it was sent, while group middlewares and handlers see the normalized one.
+#[derive(Debug, Clone)]
+pub struct PathNormalization {
+    /// It collapses repeated slashes, e.g. `/echo//abc` is served as `/echo/abc`
+    pub merge_slashes: bool,
+    /// It resolves `.` and `..` segments, e.g. `/files/../x` is served as `/x`
+    pub resolve_dot_segments: bool,
+    pub trailing_slash: TrailingSlash,
+    /// It answers with a redirect to the normalized path instead of serving it, 301 Moved
+    /// Permanently for GET and HEAD and 308 Permanent Redirect for the rest of methods so the
+    /// client repeats the same request
+    pub redirect: bool,
+}
+
+impl Default for PathNormalization {
+    fn default() -> Self {
+        Self {
+            merge_slashes: true,
+            resolve_dot_segments: true,
+            trailing_slash: TrailingSlash::Strict,
+            redirect: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// `/echo/abc/` and `/echo/abc` are different paths
+    Strict,
+    /// When only one of `/echo/abc/` and `/echo/abc` is registered, it serves both
+    Ignore,
+}
+
+/// What the server does with new connections while every worker is busy and the queue is full
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverloadBehavior {
+    /// It answers 503 Service Unavailable with a `Retry-After` header and closes the connection
+    ServiceUnavailable { retry_after: Duration },
+    /// It closes the connection without answering
+    Close,
+}
+
+impl Default for OverloadBehavior {
+    fn default() -> Self {
+        OverloadBehavior::ServiceUnavailable {
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PoolOptions {
+    workers: usize,
+    queue_depth: usize,
+    overload_behavior: OverloadBehavior,
+}
+
+/// Settings that every connection thread needs a copy of
+#[derive(Debug, Clone)]
+struct ConnectionOptions {
+    limits: RequestLimits,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    keep_alive_timeout: Duration,
+    max_requests_per_connection: usize,
+}
+
+impl Default for ServerHTTP {
+    fn default() -> Self {
+        Self {
+            default_host: VirtualHost::default(),
+            virtual_hosts: vec![],
+            middlewares: vec![],
+            group_middlewares: vec![],
+            compression: true,
+            state: Arc::default(),
+            path_normalization: PathNormalization::default(),
+            connection_options: ConnectionOptions {
+                limits: RequestLimits::default(),
+                read_timeout: DEFAULT_READ_TIMEOUT,
+                write_timeout: DEFAULT_WRITE_TIMEOUT,
+                keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+                max_requests_per_connection: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+            },
+            pool_options: PoolOptions {
+                workers: DEFAULT_WORKERS,
+                queue_depth: DEFAULT_QUEUE_DEPTH,
+                overload_behavior: OverloadBehavior::default(),
+            },
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        }
+    }
+}
+
+impl std::fmt::Debug for ServerHTTP {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerHTTP")
+            .field("default_host", &self.default_host)
+            .field("virtual_hosts", &self.virtual_hosts)
+            .field("middlewares", &self.middlewares.len())
+            .field(
+                "group_middlewares",
+                &self
+                    .group_middlewares
+                    .iter()
+                    .map(|(prefix, _)| prefix)
+                    .collect::<Vec<_>>(),
+            )
+            .field("compression", &self.compression)
+            .field("state", &self.state)
+            .field("path_normalization", &self.path_normalization)
+            .field("connection_options", &self.connection_options)
+            .field("pool_options", &self.pool_options)
+            .field("drain_timeout", &self.drain_timeout)
+            .finish()
+    }
 }
 
 impl ServerHTTP {
-    pub fn listen(&self, host: String) {
-        let listener = TcpListener::bind(host).expect("Error to connect with the host");
-
-        for stream in listener.incoming() {
-            let handlers = self.handlers.clone();
-            let public_folder = self.public_folder.clone();
-
-            match stream {
-                Ok(mut stream) => {
-                    std::thread::spawn(move || {
-                        let mut req = Request::new(&mut stream);
-                        let res = ResponseBuilder::new(&mut stream)
-                            .with_compression_schemas(req.get_compression_schemas())
-                            .with_public_folder(public_folder)
-                            .with_version(req.version.clone())
-                            .build();
-
-                        let handler = handlers.iter().find(|h| {
-                            let pattern = h.0;
-
-                            pattern.contains_pattern(&req)
-                        });
-
-                        match handler {
-                            Some(h) => {
-                                let pattern = h.0;
-                                let handle_fn = h.1;
-
-                                req.set_path_params(&pattern.get_path());
-
-                                handle_fn(req, res);
-                            }
-                            None => {
-                                res.status_code(StatusCode::NotFound).send();
-                            }
-                        }
-                    });
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    continue;
+    /// It serves requests, blocking forever as there is no handle to shut the server down.
+    /// Use `start` or `serve` to get a `ShutdownHandle` for a graceful shutdown
+    pub fn listen(&self, address: impl ToSocketAddrs) -> Result<(), ServerError> {
+        self.start(address)?.wait();
+
+        Ok(())
+    }
+
+    /// It binds the address and starts serving requests in the background, see `serve`
+    pub fn start(&self, address: impl ToSocketAddrs) -> Result<ShutdownHandle, ServerError> {
+        self.serve(Self::bind(address)?)
+    }
+
+    /// It binds the address without serving requests yet. Binding port 0 gets a free port
+    /// from the system, which can be read back with `local_addr` before serving.
+    pub fn bind(address: impl ToSocketAddrs) -> Result<TcpListener, ServerError> {
+        TcpListener::bind(address).map_err(ServerError::Bind)
+    }
+
+    /// It starts serving the requests received by the listener in the background and returns
+    /// the handle to shut the server down
+    pub fn serve(&self, listener: TcpListener) -> Result<ShutdownHandle, ServerError> {
+        self.serve_listeners(vec![listener])
+    }
+
+    /// It starts serving the requests received by every listener in the background, e.g. one
+    /// for IPv4 and one for IPv6. The connections of every listener share the same workers.
+    pub fn serve_listeners(
+        &self,
+        listeners: Vec<TcpListener>,
+    ) -> Result<ShutdownHandle, ServerError> {
+        if listeners.is_empty() {
+            return Err(ServerError::NoListeners);
+        }
+
+        // A name used twice is a mistake found when the server starts, like conflicting paths
+        for host in std::iter::once(&self.default_host).chain(&self.virtual_hosts) {
+            if let Some(name) = host.routes.duplicated_names().first() {
+                return Err(ServerError::DuplicatedRouteName(name.to_string()));
+            }
+        }
+
+        let local_addrs = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(ServerError::Io)?;
+
+        let shutdown = ShutdownHandle::new(local_addrs);
+
+        let application = self.build_application(shutdown.clone());
+        let options = self.connection_options.clone();
+
+        let pool = WorkerPool::new(
+            self.pool_options.workers,
+            self.pool_options.queue_depth,
+            move |(stream, _connection): (TcpStream, ConnectionGuard)| {
+                handle_connection(stream, &application, options.clone());
+            },
+        )
+        .map_err(ServerError::Io)?;
+
+        let pool = Arc::new(pool);
+        let mut accept_threads = vec![];
+
+        for listener in listeners {
+            let pool = Arc::clone(&pool);
+            let overload_behavior = self.pool_options.overload_behavior.clone();
+            let server_shutdown = shutdown.clone();
+
+            let accept_thread = std::thread::Builder::new().name("http-accept".to_string());
+
+            // The listener and the pool are dropped when the loop ends, so no more connections
+            // are accepted and idle workers finish once every accept loop ended
+            let result = accept_thread.spawn(move || {
+                accept_connections(&listener, &pool, &overload_behavior, &server_shutdown);
+            });
+
+            match result {
+                Ok(accept_thread) => accept_threads.push(accept_thread),
+                Err(err) => {
+                    shutdown.shutdown();
+
+                    return Err(ServerError::Io(err));
                 }
-                Err(e) => {
-                    panic!("Error when listening messages: {}", e);
+            }
+        }
+
+        let drain_timeout = self.drain_timeout;
+        let server_shutdown = shutdown.clone();
+
+        let drain_thread = std::thread::Builder::new().name("http-shutdown".to_string());
+
+        let result = drain_thread.spawn(move || {
+            for accept_thread in accept_threads {
+                if accept_thread.join().is_err() {
+                    eprintln!("Accept loop stopped by a panic");
                 }
             }
+
+            let open_connections = server_shutdown.drain(drain_timeout);
+
+            if open_connections > 0 {
+                eprintln!(
+                    "Drain timeout expired, closed {} connections",
+                    open_connections
+                );
+            }
+
+            server_shutdown.mark_stopped();
+        });
+
+        if let Err(err) = result {
+            shutdown.shutdown();
+
+            return Err(ServerError::Io(err));
+        }
+
+        Ok(shutdown)
+    }
+
+    fn build_application(&self, shutdown: ShutdownHandle) -> Application {
+        let mut middlewares: Vec<MiddlewareFn> = vec![];
+
+        // Compression runs first so it wraps every other middleware and sees the final body
+        if self.compression {
+            middlewares.push(Arc::new(middleware::compression));
         }
+
+        middlewares.extend(self.middlewares.iter().cloned());
+
+        let mut virtual_hosts = self.virtual_hosts.clone();
+
+        for virtual_host in &mut virtual_hosts {
+            if virtual_host.public_folder.is_none() {
+                virtual_host.public_folder = self.default_host.public_folder.clone();
+            }
+        }
+
+        virtual_hosts.sort_by_key(|virtual_host| std::cmp::Reverse(virtual_host.specificity()));
+
+        Application {
+            default_host: self.default_host.clone(),
+            virtual_hosts,
+            middlewares,
+            group_middlewares: self.group_middlewares.clone(),
+            state: Arc::clone(&self.state),
+            path_normalization: self.path_normalization.clone(),
+            shutdown,
+        }
+    }
+
+    /// It registers a handler and returns its route, so middlewares that only apply to it can be
+    /// added. The route is served for the default host, see `virtual_host` for other hosts.
+    ///
+    /// It panics when the path is not valid or conflicts with a registered route, so mistakes
+    /// are found when the server starts instead of serving the wrong handler.
+    pub fn handle_fn<F>(&mut self, method: Method, path: &str, handler_fn: F) -> &mut Route
+    where
+        F: Fn(Request, Response) + Send + Sync + 'static,
+    {
+        self.default_host.handle_fn(method, path, handler_fn)
+    }
+
+    /// It registers a handler that returns its response instead of sending it. The server sends
+    /// whatever the handler returns, and errors are converted with `IntoResponse`, so every
+    /// request gets exactly one response.
+    pub fn handle<F, E>(&mut self, method: Method, path: &str, handler: F) -> &mut Route
+    where
+        F: Fn(Request) -> Result<Response, E> + Send + Sync + 'static,
+        E: IntoResponse,
+    {
+        self.default_host.handle(method, path, handler)
+    }
+
+    /// It registers a handler like `handle`, returning the error instead of panicking when the
+    /// route is not valid
+    pub(crate) fn try_handle<F, E>(
+        &mut self,
+        method: Method,
+        path: &str,
+        handler: F,
+    ) -> Result<&mut Route, RouteError>
+    where
+        F: Fn(Request) -> Result<Response, E> + Send + Sync + 'static,
+        E: IntoResponse,
+    {
+        self.default_host.try_handle(method, path, handler)
+    }
+
+    /// It returns the virtual host for the host name, creating it the first time. Requests are
+    /// served by the virtual host matching their `Host` header, exact names first and then the
+    /// most specific wildcard, or by the default host when none matches. Virtual hosts without
+    /// their own public folder use the server one.
+    pub fn virtual_host(&mut self, host: &str) -> &mut VirtualHost {
+        let virtual_host = VirtualHost::new(host);
+
+        let position = self
+            .virtual_hosts
+            .iter()
+            .position(|existing| existing.get_host() == virtual_host.get_host())
+            .unwrap_or_else(|| {
+                self.virtual_hosts.push(virtual_host);
+
+                self.virtual_hosts.len() - 1
+            });
+
+        &mut self.virtual_hosts[position]
+    }
+
+    /// It lists the registered routes of every host, starting with the default one, sorted by
+    /// path and method
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        let mut routes = self.default_host.routes();
+
+        let mut virtual_hosts: Vec<&VirtualHost> = self.virtual_hosts.iter().collect();
+        virtual_hosts.sort_by_key(|virtual_host| virtual_host.get_host());
+
+        for virtual_host in virtual_hosts {
+            routes.extend(virtual_host.routes());
+        }
+
+        routes
+    }
+
+    /// It builds the path of the route registered with the name, e.g. `/files/a.txt` for
+    /// `url_for("file", &[("filename", "a.txt")])` when the route path is `/files/{filename}`
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        self.default_host.url_for(name, params)
+    }
+
+    /// It registers every route of the router as it is. Like `handle_fn`, it panics when a route
+    /// conflicts with one already registered.
+    pub fn merge(&mut self, router: Router) {
+        self.default_host.merge(router);
+    }
+
+    /// It registers every route of the router under the prefix, e.g. `/users/{id}` nested in
+    /// `/api` is served at `/api/users/{id}`
+    pub fn nest(&mut self, prefix: &str, router: Router) {
+        self.default_host.nest(prefix, router);
+    }
+
+    /// It adds a middleware that runs for every request, including the ones that do not match
+    /// any handler. Middlewares run in the order they were added.
+    pub fn use_middleware<F>(&mut self, middleware: F)
+    where
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static,
+    {
+        self.middlewares.push(Arc::new(middleware));
     }
 
-    pub fn handle_fn(&mut self, method: &str, path: &str, handler_fn: fn(Request, Response)) {
-        let handler_pattern = HandlerPattern(method.to_string(), path.to_string());
+    /// It adds a middleware that only runs for requests whose path is under the prefix, e.g.
+    /// `/admin` matches `/admin` and `/admin/users` but not `/administrator`
+    pub fn use_middleware_at<F>(&mut self, prefix: &str, middleware: F)
+    where
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static,
+    {
+        let prefix = prefix.trim_end_matches('/').to_string();
 
-        self.handlers.entry(handler_pattern).or_insert(handler_fn);
+        self.group_middlewares.push((prefix, Arc::new(middleware)));
+    }
+
+    /// It enables or disables the compression of response bodies, enabled by default
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    /// It registers a value that handlers can read with `req.state::<T>()`. Each type can only
+    /// have one value, so registering the same type twice replaces the previous one. Values are
+    /// shared between threads, so mutable state needs its own synchronization (e.g. `Mutex` or
+    /// atomics).
+    pub fn with_state<T: Send + Sync + 'static>(mut self, state: T) -> Self {
+        Arc::make_mut(&mut self.state).insert(state);
+
+        self
     }
 
     pub fn set_public_folder(&mut self, public_folder: &str) {
-        self.public_folder = Some(public_folder.to_string());
+        self.default_host.set_public_folder(public_folder);
+    }
+
+    /// It sets the maximum number of bytes accepted as request body. Bigger requests are
+    /// rejected with 413 Payload Too Large.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.connection_options.limits.max_body_size = max_body_size;
+    }
+
+    /// It sets how request paths are normalized before looking for their handler
+    pub fn set_path_normalization(&mut self, path_normalization: PathNormalization) {
+        self.path_normalization = path_normalization;
+    }
+
+    /// It sets the limits applied while parsing requests
+    pub fn set_request_limits(&mut self, limits: RequestLimits) {
+        self.connection_options.limits = limits;
+    }
+
+    pub fn get_request_limits(&self) -> &RequestLimits {
+        &self.connection_options.limits
+    }
+
+    /// It sets how long a client has to send a complete request, from its first byte until the
+    /// end of the body. It panics when the timeout is 0, as no request could be read.
+    pub fn set_read_timeout(&mut self, read_timeout: Duration) {
+        assert!(
+            !read_timeout.is_zero(),
+            "The read timeout must be greater than 0"
+        );
+
+        self.connection_options.read_timeout = read_timeout;
+    }
+
+    /// It sets how long a client has to read each response, from its first byte until the end of
+    /// the body. It panics when the timeout is 0, as no response could be written.
+    pub fn set_write_timeout(&mut self, write_timeout: Duration) {
+        assert!(
+            !write_timeout.is_zero(),
+            "The write timeout must be greater than 0"
+        );
+
+        self.connection_options.write_timeout = write_timeout;
+    }
+
+    /// It sets how long an idle persistent connection is kept open waiting for the next request.
+    /// It panics when the timeout is 0, as connections would be closed before their first
+    /// request.
+    pub fn set_keep_alive_timeout(&mut self, keep_alive_timeout: Duration) {
+        assert!(
+            !keep_alive_timeout.is_zero(),
+            "The keep-alive timeout must be greater than 0"
+        );
+
+        self.connection_options.keep_alive_timeout = keep_alive_timeout;
+    }
+
+    /// It sets how many connections are served at the same time. Persistent connections keep
+    /// their worker busy until they are closed.
+    pub fn set_workers(&mut self, workers: usize) {
+        self.pool_options.workers = workers;
+    }
+
+    /// It sets how many accepted connections can wait for a free worker. When the queue is full
+    /// new connections are handled following the overload behavior.
+    pub fn set_queue_depth(&mut self, queue_depth: usize) {
+        self.pool_options.queue_depth = queue_depth;
+    }
+
+    /// It sets how long the server waits for in-flight requests when shutting down before
+    /// closing their connections
+    pub fn set_drain_timeout(&mut self, drain_timeout: Duration) {
+        self.drain_timeout = drain_timeout;
+    }
+
+    pub fn set_overload_behavior(&mut self, overload_behavior: OverloadBehavior) {
+        self.pool_options.overload_behavior = overload_behavior;
+    }
+
+    /// It sets how many requests are served on the same connection before closing it
+    pub fn set_max_requests_per_connection(&mut self, max_requests: usize) {
+        self.connection_options.max_requests_per_connection = max_requests;
+    }
+}
+
+/// It serves every request sent on the connection until the client asks to close it, the
+/// connection stays idle longer than the keep-alive timeout or the maximum number of requests is
+/// reached.
+fn handle_connection(stream: TcpStream, application: &Application, options: ConnectionOptions) {
+    let mut reader = match stream.try_clone() {
+        Ok(reader_stream) => RequestReader::new(reader_stream),
+        Err(err) => {
+            eprintln!("Error cloning the connection stream: {}", err);
+
+            return;
+        }
+    };
+
+    let mut requests_served: usize = 0;
+
+    loop {
+        // The read timeout starts when the request starts arriving, not while idle
+        reader.set_deadline(None);
+
+        if !wait_for_request(&stream, &mut reader, application, &options, requests_served) {
+            return;
+        }
+
+        reader.set_deadline(Some(Instant::now() + options.read_timeout));
+
+        let mut req = match Request::new(&mut reader, &options.limits) {
+            Ok(req) => req,
+            Err(err) => {
+                eprintln!("{}", err);
+
+                // There is no point on answering when the connection is broken
+                if let Some(status_code) = parse_error_status_code(&err) {
+                    let result = ResponseBuilder::new()
+                        .with_header("Connection", "close")
+                        .build()
+                        .status_code(status_code)
+                        .write_to(&mut DeadlineWriter::new(&stream, &options), false);
+
+                    if let Err(err) = result {
+                        log_write_error(&err);
+                    }
+                }
+
+                return;
+            }
+        };
+
+        requests_served += 1;
+
+        req.set_state(Arc::clone(&application.state));
+
+        let keep_alive =
+            req.is_keep_alive() && requests_served < options.max_requests_per_connection;
+        let version = req.version.clone();
+        // HEAD is answered like GET, but the response body is not written
+        let omit_body = req.method == Method::Head;
+
+        let mut res =
+            Next::new(&application.middlewares, &|req| application.dispatch(req)).run(req);
+
+        res.set_version(&version);
+
+        // While shutting down, connections are closed after the request they are serving
+        let keep_alive = keep_alive && !application.shutdown.is_shutting_down();
+
+        if !keep_alive {
+            res.headers_mut().insert("Connection", "close");
+        } else if version == "HTTP/1.0" {
+            // HTTP/1.0 clients assume the connection is closed unless told otherwise
+            res.headers_mut().insert("Connection", "keep-alive");
+        }
+
+        if let Err(err) = res.write_to(&mut DeadlineWriter::new(&stream, &options), omit_body) {
+            log_write_error(&err);
+
+            // Nothing was written when a handler set an invalid header, so the client still
+            // gets an answer
+            if err.kind() == io::ErrorKind::InvalidData {
+                let result = ResponseBuilder::new()
+                    .with_header("Connection", "close")
+                    .build()
+                    .status_code(StatusCode::InternalServer)
+                    .write_to(&mut DeadlineWriter::new(&stream, &options), omit_body);
+
+                if let Err(err) = result {
+                    log_write_error(&err);
+                }
+            }
+
+            return;
+        }
+
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+/// It waits for the next request on the connection. While waiting the connection is idle, so the
+/// keep-alive timeout applies instead of the read timeout. It returns `false` when the connection
+/// must be closed: the client closed it, it stayed idle too long, or the server is shutting down
+/// and the connection already got an answer.
+fn wait_for_request(
+    stream: &TcpStream,
+    reader: &mut RequestReader,
+    application: &Application,
+    options: &ConnectionOptions,
+    requests_served: usize,
+) -> bool {
+    let deadline = Instant::now() + options.keep_alive_timeout;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return false;
+        }
+
+        // It wakes up regularly to notice the shutdown, instead of blocking for the whole
+        // keep-alive timeout
+        if let Err(err) = stream.set_read_timeout(Some(remaining.min(SHUTDOWN_POLL_INTERVAL))) {
+            eprintln!("Error setting the keep-alive timeout: {}", err);
+
+            return false;
+        }
+
+        match reader.wait_for_request() {
+            Ok(received) => return received,
+            // A connection accepted right before the shutdown still gets its request served
+            Err(RequestParseError::Timeout)
+                if application.shutdown.is_shutting_down() && requests_served > 0 =>
+            {
+                return false;
+            }
+            Err(RequestParseError::Timeout) => {}
+            Err(err) => {
+                eprintln!("{}", err);
+
+                return false;
+            }
+        }
+    }
+}
+
+/// It hands every accepted connection over to the workers until the server starts shutting down
+fn accept_connections(
+    listener: &TcpListener,
+    pool: &WorkerPool<(TcpStream, ConnectionGuard)>,
+    overload_behavior: &OverloadBehavior,
+    shutdown: &ShutdownHandle,
+) {
+    for stream in listener.incoming() {
+        // The connection that woke up the loop to shut down is not served
+        if shutdown.is_shutting_down() {
+            return;
+        }
+
+        match stream {
+            Ok(stream) => {
+                let connection = shutdown.connection_opened(&stream);
+
+                if let Err((stream, _connection)) = pool.try_submit((stream, connection)) {
+                    reject_connection(stream, overload_behavior);
+                }
+            }
+            // The client gave up before the connection was accepted
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::Interrupted
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::ConnectionReset
+                ) => {}
+            Err(err) => {
+                eprintln!("Error accepting a connection: {}", err);
+
+                std::thread::sleep(ACCEPT_ERROR_BACKOFF);
+            }
+        }
+    }
+}
+
+/// It turns away a connection that no worker can serve
+fn reject_connection(mut stream: TcpStream, overload_behavior: &OverloadBehavior) {
+    eprintln!("Server overloaded, rejecting connection");
+
+    let OverloadBehavior::ServiceUnavailable { retry_after } = overload_behavior else {
+        return;
+    };
+
+    if let Err(err) = stream.set_write_timeout(Some(OVERLOAD_WRITE_TIMEOUT)) {
+        eprintln!("Error setting the write timeout: {}", err);
+
+        return;
+    }
+
+    let result = ResponseBuilder::new()
+        .with_header("Retry-After", &retry_after.as_secs().max(1).to_string())
+        .with_header("Connection", "close")
+        .build()
+        .status_code(StatusCode::ServiceUnavailable)
+        .write_to(&mut stream, false)
+        .and_then(|()| stream.shutdown(Shutdown::Write));
+
+    if let Err(err) = result {
+        log_write_error(&err);
+    }
+}
+
+/// It writes a response to the client stream with a deadline for the whole of it, the same way
+/// `RequestReader` reads a request, so a client reading slowly can not hold the worker either
+struct DeadlineWriter<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> DeadlineWriter<'a> {
+    fn new(stream: &'a TcpStream, options: &ConnectionOptions) -> Self {
+        Self {
+            stream,
+            deadline: Instant::now() + options.write_timeout,
+        }
+    }
+}
+
+impl io::Write for DeadlineWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        // Each write waits at most until the deadline
+        self.stream.set_write_timeout(Some(remaining))?;
+
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Clients closing the connection before reading the whole response are expected, so they are
+/// not reported as server errors
+fn log_write_error(err: &io::Error) {
+    match err.kind() {
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted => {
+            eprintln!("Client closed the connection before receiving the response");
+        }
+        // Both kinds are returned depending on the platform when the write timeout expires
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            eprintln!("Timeout writing the response, the client is not reading it");
+        }
+        _ => eprintln!("Error writing the response: {}", err),
+    }
+}
+
+impl Application {
+    /// It finds the handler for the request and runs it behind the group and route middlewares.
+    /// When there is no handler, the fallback response still goes through the group middlewares.
+    fn dispatch(&self, mut req: Request) -> Response {
+        let host = self.select_host(&req);
+
+        if let Some(location) = self.normalize_path(host, &mut req) {
+            let status_code = match req.method {
+                Method::Get | Method::Head => StatusCode::MovedPermanently,
+                _ => StatusCode::PermanentRedirect,
+            };
+
+            return ResponseBuilder::new()
+                .build()
+                .status_code(status_code)
+                .header("Location", &location);
+        }
+
+        let mut middlewares: Vec<MiddlewareFn> = self
+            .group_middlewares
+            .iter()
+            .filter(|(prefix, _)| is_under_prefix(&req.path, prefix))
+            .map(|(_, middleware)| Arc::clone(middleware))
+            .collect();
+
+        let handler = host.find_route(req.raw_path(), &req.method).or_else(|| {
+            // HEAD is answered by the GET handler, the response body is dropped when writing
+            if req.method != Method::Head {
+                return None;
+            }
+
+            host.find_route(req.raw_path(), &Method::Get)
+        });
+
+        match handler {
+            Some((route, path_params)) => {
+                req.path_params = path_params;
+
+                middlewares.extend(route.middlewares.iter().cloned());
+
+                Next::new(&middlewares, &|req| {
+                    self.call_handler(host, &route.handler, req)
+                })
+                .run(req)
+            }
+            None => Next::new(&middlewares, &|req| self.fallback(host, &req)).run(req),
+        }
+    }
+
+    /// It returns the virtual host serving the request host, or the default one
+    fn select_host(&self, req: &Request) -> &VirtualHost {
+        let Some(host) = req.host() else {
+            return &self.default_host;
+        };
+
+        self.virtual_hosts
+            .iter()
+            .find(|virtual_host| virtual_host.matches(&host))
+            .unwrap_or(&self.default_host)
+    }
+
+    /// It normalizes the request path following the server policy. It returns where the client
+    /// should be redirected instead, if the policy asks for it and the path changed.
+    fn normalize_path(&self, host: &VirtualHost, req: &mut Request) -> Option<String> {
+        let options = &self.path_normalization;
+        let raw_path = req.raw_path();
+
+        // Targets such as `*` in `OPTIONS *` are not paths
+        if !raw_path.starts_with('/') {
+            return None;
+        }
+
+        let mut path = raw_path.to_string();
+
+        if options.merge_slashes {
+            path = url::merge_slashes(&path);
+        }
+
+        if options.resolve_dot_segments {
+            path = url::remove_dot_segments(&path);
+        }
+
+        if options.trailing_slash == TrailingSlash::Ignore && path != "/" && !host.has_route(&path)
+        {
+            let alternative_path = match path.strip_suffix('/') {
+                Some(path) => path.to_string(),
+                None => format!("{}/", path),
+            };
+
+            if host.has_route(&alternative_path) {
+                path = alternative_path;
+            }
+        }
+
+        if path == raw_path {
+            return None;
+        }
+
+        if !options.redirect {
+            req.set_raw_path(&path);
+
+            return None;
+        }
+
+        // A location starting with `//` would point to another host
+        let path = format!("/{}", path.trim_start_matches('/'));
+
+        let location = match req.target.split_once('?') {
+            Some((_, raw_query)) => format!("{}?{}", path, raw_query),
+            None => path,
+        };
+
+        Some(url::percent_encode_target(&location))
+    }
+
+    fn call_handler(&self, host: &VirtualHost, handler: &HandlerFn, req: Request) -> Response {
+        let sink = ResponseSink::default();
+
+        let res = ResponseBuilder::new()
+            .with_public_folder(host.public_folder.clone())
+            .build()
+            .with_sink(Arc::clone(&sink));
+
+        handler(req, res);
+
+        let sent_response = sink.lock().unwrap_or_else(|err| err.into_inner()).take();
+
+        sent_response.unwrap_or_else(|| {
+            eprintln!("Handler finished without sending a response");
+
+            ResponseBuilder::new()
+                .build()
+                .status_code(StatusCode::InternalServer)
+        })
+    }
+
+    /// It answers requests without a handler: OPTIONS gets the allowed methods, a path that only
+    /// exists for other methods gets 405 and anything else 404
+    fn fallback(&self, host: &VirtualHost, req: &Request) -> Response {
+        let res = ResponseBuilder::new().build();
+        let allowed_methods = get_allowed_methods(host, req);
+
+        if allowed_methods.is_empty() {
+            res.status_code(StatusCode::NotFound)
+        } else if req.method == Method::Options {
+            res.header("Allow", &allowed_methods)
+        } else {
+            // The path exists but not for this method
+            res.status_code(StatusCode::MethodNotAllowed)
+                .header("Allow", &allowed_methods)
+        }
+    }
+}
+
+/// It lists the methods that can be used with the request path, including the ones answered
+/// automatically by the server. It is empty when no handler matches the path. `OPTIONS *` lists
+/// every method registered in the host.
+fn get_allowed_methods(host: &VirtualHost, req: &Request) -> String {
+    let mut methods: BTreeSet<Method> = if req.target == "*" {
+        host.routes
+            .routes()
+            .into_iter()
+            .map(|(pattern, _)| pattern.get_method())
+            .collect()
+    } else {
+        host.matching_methods(req.raw_path())
+    };
+
+    if methods.is_empty() {
+        return String::new();
+    }
+
+    if methods.contains(&Method::Get) {
+        methods.insert(Method::Head);
+    }
+
+    methods.insert(Method::Options);
+
+    methods
+        .iter()
+        .map(|method| method.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn is_under_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn parse_error_status_code(err: &RequestParseError) -> Option<StatusCode> {
+    match err {
+        RequestParseError::InvalidRequestLine
+        | RequestParseError::InvalidHeader
+        | RequestParseError::InvalidHost
+        | RequestParseError::InvalidContentLength
+        | RequestParseError::MissingContentLength
+        | RequestParseError::ConflictingBodyLength
+        | RequestParseError::InvalidChunk => Some(StatusCode::BadRequest),
+        RequestParseError::UnsupportedTransferEncoding => Some(StatusCode::NotImplemented),
+        RequestParseError::UnsupportedExpectation => Some(StatusCode::ExpectationFailed),
+        RequestParseError::Timeout => Some(StatusCode::RequestTimeout),
+        RequestParseError::BodyTooLarge => Some(StatusCode::PayloadTooLarge),
+        RequestParseError::UriTooLong => Some(StatusCode::UriTooLong),
+        RequestParseError::HeadersTooLarge => Some(StatusCode::RequestHeaderFieldsTooLarge),
+        RequestParseError::UnsupportedVersion => Some(StatusCode::HttpVersionNotSupported),
+        RequestParseError::Io(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        sync::Barrier,
+    };
+
+    use super::*;
+
+    fn serve(server: &ServerHTTP) -> ShutdownHandle {
+        server
+            .serve(ServerHTTP::bind("127.0.0.1:0").unwrap())
+            .unwrap()
+    }
+
+    fn connect(shutdown: &ShutdownHandle) -> TcpStream {
+        let stream = TcpStream::connect(shutdown.local_addr()).unwrap();
+
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        stream
+    }
+
+    /// It sends the raw request and returns everything received until the server closes the
+    /// connection
+    fn send(shutdown: &ShutdownHandle, raw: &str) -> String {
+        let mut stream = connect(shutdown);
+        let mut response = String::new();
+
+        stream.write_all(raw.as_bytes()).unwrap();
+        stream.read_to_string(&mut response).unwrap();
+
+        response
+    }
+
+    #[test]
+    fn it_stops_writing_to_a_client_that_does_not_read_after_the_write_timeout() {
+        let mut server = ServerHTTP::default();
+
+        server.set_workers(1);
+        server.set_write_timeout(Duration::from_millis(200));
+        server.handle_fn(Method::Get, "/large", |_, res| {
+            res.body(&vec![b'a'; 64 * 1024 * 1024]).send();
+        });
+        server.handle_fn(Method::Get, "/small", |_, res| res.send_text("small"));
+
+        let shutdown = serve(&server);
+
+        // It never reads the response, so the only worker would be busy writing it
+        let mut slow_client = connect(&shutdown);
+        slow_client
+            .write_all(b"GET /large HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+
+        let started_at = Instant::now();
+        let response = send(
+            &shutdown,
+            "GET /small HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+
+        assert!(response.ends_with("\r\n\r\nsmall"), "{}", response);
+        assert!(started_at.elapsed() < Duration::from_secs(3));
+
+        shutdown.shutdown();
+        shutdown.wait();
+    }
+
+    /// It returns the value of the header in a raw response
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        let (head, _) = response.split_once("\r\n\r\n")?;
+
+        head.split("\r\n").skip(1).find_map(|line| {
+            let (field_name, value) = line.split_once(':')?;
+
+            field_name
+                .eq_ignore_ascii_case(name)
+                .then_some(value.trim())
+        })
+    }
+
+    /// It serves a few routes answering with the path they received, following the
+    /// normalization options
+    fn serve_normalized(path_normalization: PathNormalization) -> ShutdownHandle {
+        let mut server = ServerHTTP::default();
+
+        server.set_path_normalization(path_normalization);
+
+        for path in ["/a", "/a/b", "/x", "/dir/", "/file"] {
+            server.handle_fn(Method::Get, path, |req, res| {
+                let target = req.target.clone();
+
+                res.send_text(&target);
+            });
+        }
+
+        serve(&server)
+    }
+
+    /// It returns the `Location` of the redirect, or the body with the target that was served
+    fn get(shutdown: &ShutdownHandle, target: &str) -> String {
+        let response = send(
+            shutdown,
+            &format!(
+                "GET {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+                target
+            ),
+        );
+
+        match header(&response, "Location") {
+            Some(location) => format!("Location: {}", location),
+            None => response
+                .split_once("\r\n\r\n")
+                .map(|(_, body)| body.to_string())
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn it_redirects_to_the_normalized_path() {
+        let shutdown = serve_normalized(PathNormalization {
+            redirect: true,
+            ..PathNormalization::default()
+        });
+
+        assert_eq!(get(&shutdown, "/a/b/.."), "Location: /a/");
+        assert_eq!(get(&shutdown, "/a/./b"), "Location: /a/b");
+        assert_eq!(get(&shutdown, "/.."), "Location: /");
+        assert_eq!(get(&shutdown, "/a//b"), "Location: /a/b");
+        assert_eq!(get(&shutdown, "/a/b"), "/a/b");
+
+        shutdown.shutdown();
+        shutdown.wait();
+    }
+
+    #[test]
+    fn it_keeps_the_query_string_when_redirecting() {
+        let shutdown = serve_normalized(PathNormalization {
+            redirect: true,
+            ..PathNormalization::default()
+        });
+
+        assert_eq!(
+            get(&shutdown, "/a/./b?x=1&y=%20&x=2"),
+            "Location: /a/b?x=1&y=%20&x=2"
+        );
+        assert_eq!(get(&shutdown, "/a//b?"), "Location: /a/b?");
+
+        shutdown.shutdown();
+        shutdown.wait();
+    }
+
+    #[test]
+    fn it_never_redirects_to_a_protocol_relative_location() {
+        let shutdown = serve_normalized(PathNormalization {
+            redirect: true,
+            ..PathNormalization::default()
+        });
+
+        assert_eq!(get(&shutdown, "//x"), "Location: /x");
+        assert_eq!(
+            get(&shutdown, "//evil.example/a"),
+            "Location: /evil.example/a"
+        );
+
+        shutdown.shutdown();
+        shutdown.wait();
+
+        // Without merging slashes, resolving `..` could still leave `//` at the start
+        let shutdown = serve_normalized(PathNormalization {
+            merge_slashes: false,
+            redirect: true,
+            ..PathNormalization::default()
+        });
+
+        assert_eq!(
+            get(&shutdown, "/..//evil.example"),
+            "Location: /evil.example"
+        );
+
+        shutdown.shutdown();
+        shutdown.wait();
+    }
+
+    #[test]
+    fn it_serves_the_normalized_path_without_redirecting() {
+        let shutdown = serve_normalized(PathNormalization::default());
+
+        assert_eq!(get(&shutdown, "/a//b"), "/a/b");
+        assert_eq!(get(&shutdown, "/x/../a/b?q=1"), "/a/b?q=1");
+
+        shutdown.shutdown();
+        shutdown.wait();
+    }
+
+    #[test]
+    fn it_serves_the_path_with_or_without_trailing_slash_when_ignored() {
+        let shutdown = serve_normalized(PathNormalization {
+            trailing_slash: TrailingSlash::Ignore,
+            ..PathNormalization::default()
+        });
+
+        assert_eq!(get(&shutdown, "/dir"), "/dir/");
+        assert_eq!(get(&shutdown, "/file/"), "/file");
+        assert_eq!(get(&shutdown, "/dir/"), "/dir/");
+
+        shutdown.shutdown();
+        shutdown.wait();
+
+        let shutdown = serve_normalized(PathNormalization {
+            trailing_slash: TrailingSlash::Ignore,
+            redirect: true,
+            ..PathNormalization::default()
+        });
+
+        assert_eq!(get(&shutdown, "/dir?q=1"), "Location: /dir/?q=1");
+        assert_eq!(get(&shutdown, "/file/"), "Location: /file");
+        assert_eq!(get(&shutdown, "/a/b/.."), "Location: /a");
+
+        shutdown.shutdown();
+        shutdown.wait();
+    }
+
+    #[test]
+    fn it_keeps_trailing_slashes_when_strict() {
+        let shutdown = serve_normalized(PathNormalization::default());
+
+        let response = send(
+            &shutdown,
+            "GET /dir HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+        shutdown.shutdown();
+        shutdown.wait();
+    }
+
+    /// It reads one response of a persistent connection, whose end is given by `Content-Length`
+    fn read_response(stream: &mut TcpStream) -> String {
+        let mut received: Vec<u8> = vec![];
+        let mut chunk = [0u8; 1024];
+
+        loop {
+            let response = String::from_utf8_lossy(&received).to_string();
+
+            if let Some((head, body)) = response.split_once("\r\n\r\n") {
+                let content_length: usize =
+                    header(&response, "Content-Length").map_or(0, |value| value.parse().unwrap());
+
+                if body.len() >= content_length {
+                    assert!(!head.is_empty());
+
+                    return response;
+                }
+            }
+
+            let bytes_read = stream.read(&mut chunk).unwrap();
+            assert!(bytes_read > 0, "Connection closed: {:?}", response);
+
+            received.extend_from_slice(&chunk[..bytes_read]);
+        }
+    }
+
+    /// A server whose `/slow` handler lets the test know when it started and then takes the
+    /// given time to answer
+    fn slow_server(duration: Duration) -> (ServerHTTP, Arc<Barrier>) {
+        let started = Arc::new(Barrier::new(2));
+        let handler_started = Arc::clone(&started);
+
+        let mut server = ServerHTTP::default();
+
+        server.handle_fn(Method::Get, "/slow", move |_, res| {
+            handler_started.wait();
+            std::thread::sleep(duration);
+
+            res.send_text("slow");
+        });
+        server.handle_fn(Method::Get, "/fast", |_, res| res.send_text("fast"));
+
+        (server, started)
+    }
+
+    #[test]
+    fn it_finishes_in_flight_requests_with_connection_close_when_shutting_down() {
+        let (server, started) = slow_server(Duration::from_millis(200));
+        let shutdown = serve(&server);
+
+        let client_shutdown = shutdown.clone();
+        let client = std::thread::spawn(move || {
+            send(&client_shutdown, "GET /slow HTTP/1.1\r\nHost: x\r\n\r\n")
+        });
+
+        started.wait();
+        shutdown.shutdown();
+
+        let response = client.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(header(&response, "Connection"), Some("close"));
+        assert!(response.ends_with("\r\n\r\nslow"));
+
+        shutdown.wait();
+    }
+
+    #[test]
+    fn it_closes_idle_keep_alive_connections_when_shutting_down() {
+        let mut server = ServerHTTP::default();
+        server.set_keep_alive_timeout(Duration::from_secs(30));
+        server.handle_fn(Method::Get, "/fast", |_, res| res.send_text("fast"));
+
+        let shutdown = serve(&server);
+
+        let mut stream = connect(&shutdown);
+        stream
+            .write_all(b"GET /fast HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+
+        let response = read_response(&mut stream);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(header(&response, "Connection"), None);
+
+        let started_at = Instant::now();
+        shutdown.shutdown();
+
+        // The connection is closed long before the keep-alive timeout
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+
+        assert!(rest.is_empty());
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+
+        shutdown.wait();
+    }
+
+    #[test]
+    fn it_closes_the_connections_still_open_when_the_drain_timeout_expires() {
+        let (mut server, started) = slow_server(Duration::from_secs(3));
+        server.set_drain_timeout(Duration::from_millis(200));
+
+        let shutdown = serve(&server);
+
+        let client_shutdown = shutdown.clone();
+        let client = std::thread::spawn(move || {
+            send(&client_shutdown, "GET /slow HTTP/1.1\r\nHost: x\r\n\r\n")
+        });
+
+        started.wait();
+
+        let started_at = Instant::now();
+        shutdown.shutdown();
+        shutdown.wait();
+
+        // The client gets the connection closed without waiting for the handler
+        let response = client.join().unwrap();
+
+        assert_eq!(response, "");
+        assert!(started_at.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn it_answers_503_with_retry_after_when_no_worker_is_free_and_there_is_no_queue() {
+        let (mut server, started) = slow_server(Duration::from_millis(500));
+        server.set_workers(1);
+        server.set_queue_depth(0);
+        server.set_overload_behavior(OverloadBehavior::ServiceUnavailable {
+            retry_after: Duration::from_secs(7),
+        });
+
+        let shutdown = serve(&server);
+
+        // The worker needs a moment to start waiting for connections
+        std::thread::sleep(Duration::from_millis(100));
+
+        let client_shutdown = shutdown.clone();
+        let client = std::thread::spawn(move || {
+            send(
+                &client_shutdown,
+                "GET /slow HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+        });
+
+        started.wait();
+
+        let response = send(
+            &shutdown,
+            "GET /fast HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+        assert_eq!(header(&response, "Retry-After"), Some("7"));
+        assert_eq!(header(&response, "Connection"), Some("close"));
+
+        let response = client.join().unwrap();
+
+        assert!(response.ends_with("\r\n\r\nslow"), "{}", response);
+
+        shutdown.shutdown();
+        shutdown.wait();
+    }
+
+    #[test]
+    fn it_queues_connections_while_the_workers_are_busy() {
+        let (mut server, started) = slow_server(Duration::from_millis(200));
+        server.set_workers(1);
+        server.set_queue_depth(1);
+
+        let shutdown = serve(&server);
+
+        let client_shutdown = shutdown.clone();
+        let client = std::thread::spawn(move || {
+            send(
+                &client_shutdown,
+                "GET /slow HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+        });
+
+        started.wait();
+
+        // It waits in the queue until the slow request is answered
+        let response = send(
+            &shutdown,
+            "GET /fast HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+
+        assert!(response.ends_with("\r\n\r\nfast"), "{}", response);
+        assert!(client.join().unwrap().ends_with("\r\n\r\nslow"));
+
+        shutdown.shutdown();
+        shutdown.wait();
     }
 }
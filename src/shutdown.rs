@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+/// Handle of a running server, used to stop it. Shutting down stops accepting connections, lets
+/// the in-flight requests finish answering them with `Connection: close`, and closes idle
+/// connections. It can be cloned to stop the server from another thread, e.g. a signal handler.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    local_addrs: Vec<SocketAddr>,
+    shutting_down: AtomicBool,
+    /// Connections accepted and not closed yet, including the ones waiting for a worker. A clone
+    /// of each stream is kept to close the ones still open when the drain timeout expires.
+    open_connections: Mutex<HashMap<u64, Option<TcpStream>>>,
+    next_connection_id: AtomicU64,
+    connections_closed: Condvar,
+    stopped: Mutex<bool>,
+    stopped_changed: Condvar,
+}
+
+/// It counts a connection as open until it is dropped
+pub(crate) struct ConnectionGuard {
+    inner: Arc<Inner>,
+    id: u64,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(local_addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                local_addrs,
+                shutting_down: AtomicBool::new(false),
+                open_connections: Mutex::new(HashMap::new()),
+                next_connection_id: AtomicU64::new(0),
+                connections_closed: Condvar::new(),
+                stopped: Mutex::new(false),
+                stopped_changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// It returns the address the server is listening on, the first one when it listens on
+    /// several
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addrs[0]
+    }
+
+    /// It returns every address the server is listening on
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.inner.local_addrs
+    }
+
+    /// It starts shutting down the server. It returns right away, use `wait` to know when the
+    /// server stopped.
+    pub fn shutdown(&self) {
+        if self.inner.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // The accept loops are blocked waiting for a connection, so one is opened to wake up
+        // each of them
+        for local_addr in &self.inner.local_addrs {
+            if let Err(err) = TcpStream::connect(wake_up_address(*local_addr)) {
+                eprintln!("Error waking up the server to shut it down: {}", err);
+            }
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// It blocks until the server stopped, after draining the connections or closing the ones
+    /// still open when the drain timeout expired
+    pub fn wait(&self) {
+        let stopped = lock(&self.inner.stopped);
+
+        let _stopped = self
+            .inner
+            .stopped_changed
+            .wait_while(stopped, |stopped| !*stopped)
+            .unwrap_or_else(|err| err.into_inner());
+    }
+
+    pub(crate) fn connection_opened(&self, stream: &TcpStream) -> ConnectionGuard {
+        let id = self.inner.next_connection_id.fetch_add(1, Ordering::SeqCst);
+
+        // Without the clone the connection is still counted, it just can not be closed early
+        let stream = stream
+            .try_clone()
+            .inspect_err(|err| eprintln!("Error cloning the connection stream: {}", err))
+            .ok();
+
+        lock(&self.inner.open_connections).insert(id, stream);
+
+        ConnectionGuard {
+            inner: Arc::clone(&self.inner),
+            id,
+        }
+    }
+
+    /// It waits until every connection is closed. When the timeout expires first, the
+    /// connections still open are shut down, so their workers stop reading requests and writing
+    /// responses, and their number is returned.
+    pub(crate) fn drain(&self, timeout: Duration) -> usize {
+        let open_connections = lock(&self.inner.open_connections);
+
+        let (open_connections, _) = self
+            .inner
+            .connections_closed
+            .wait_timeout_while(open_connections, timeout, |open_connections| {
+                !open_connections.is_empty()
+            })
+            .unwrap_or_else(|err| err.into_inner());
+
+        for stream in open_connections.values().flatten() {
+            // The client may have closed it already
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        open_connections.len()
+    }
+
+    pub(crate) fn mark_stopped(&self) {
+        *lock(&self.inner.stopped) = true;
+
+        self.inner.stopped_changed.notify_all();
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        lock(&self.inner.open_connections).remove(&self.id);
+
+        self.inner.connections_closed.notify_all();
+    }
+}
+
+/// The counters stay valid even if a thread panicked while holding the lock
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// A server listening on every interface is reached through the loopback one
+fn wake_up_address(local_addr: SocketAddr) -> SocketAddr {
+    let ip = match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+
+    SocketAddr::new(ip, local_addr.port())
+}
@@ -0,0 +1,237 @@
+/// It defines the registered status codes together with their numeric value and reason phrase
+macro_rules! status_codes {
+    ($(($variant:ident, $code:literal, $reason:literal),)+) => {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum StatusCode {
+            $($variant,)+
+            /// Any code outside of the registry, or a registered one with a custom reason phrase.
+            /// It can only be built with `StatusCode::custom`, which validates it.
+            Custom(CustomStatusCode),
+        }
+
+        impl StatusCode {
+            pub fn as_u16(&self) -> u16 {
+                match self {
+                    $(StatusCode::$variant => $code,)+
+                    StatusCode::Custom(custom) => custom.code,
+                }
+            }
+
+            pub fn reason_phrase(&self) -> &str {
+                match self {
+                    $(StatusCode::$variant => $reason,)+
+                    StatusCode::Custom(custom) => custom.reason_phrase.as_str(),
+                }
+            }
+
+            /// It returns the registered status code for the number, if any
+            pub fn from_u16(code: u16) -> Option<Self> {
+                match code {
+                    $($code => Some(StatusCode::$variant),)+
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    (Continue, 100, "Continue"),
+    (SwitchingProtocols, 101, "Switching Protocols"),
+    (Processing, 102, "Processing"),
+    (EarlyHints, 103, "Early Hints"),
+    (Ok, 200, "OK"),
+    (Created, 201, "Created"),
+    (Accepted, 202, "Accepted"),
+    (NonAuthoritativeInformation, 203, "Non-Authoritative Information"),
+    (NoContent, 204, "No Content"),
+    (ResetContent, 205, "Reset Content"),
+    (PartialContent, 206, "Partial Content"),
+    (MultiStatus, 207, "Multi-Status"),
+    (AlreadyReported, 208, "Already Reported"),
+    (ImUsed, 226, "IM Used"),
+    (MultipleChoices, 300, "Multiple Choices"),
+    (MovedPermanently, 301, "Moved Permanently"),
+    (Found, 302, "Found"),
+    (SeeOther, 303, "See Other"),
+    (NotModified, 304, "Not Modified"),
+    (UseProxy, 305, "Use Proxy"),
+    (TemporaryRedirect, 307, "Temporary Redirect"),
+    (PermanentRedirect, 308, "Permanent Redirect"),
+    (BadRequest, 400, "Bad Request"),
+    (Unauthorized, 401, "Unauthorized"),
+    (PaymentRequired, 402, "Payment Required"),
+    (Forbidden, 403, "Forbidden"),
+    (NotFound, 404, "Not Found"),
+    (MethodNotAllowed, 405, "Method Not Allowed"),
+    (NotAcceptable, 406, "Not Acceptable"),
+    (ProxyAuthenticationRequired, 407, "Proxy Authentication Required"),
+    (RequestTimeout, 408, "Request Timeout"),
+    (Conflict, 409, "Conflict"),
+    (Gone, 410, "Gone"),
+    (LengthRequired, 411, "Length Required"),
+    (PreconditionFailed, 412, "Precondition Failed"),
+    (PayloadTooLarge, 413, "Payload Too Large"),
+    (UriTooLong, 414, "URI Too Long"),
+    (UnsupportedMediaType, 415, "Unsupported Media Type"),
+    (RangeNotSatisfiable, 416, "Range Not Satisfiable"),
+    (ExpectationFailed, 417, "Expectation Failed"),
+    (MisdirectedRequest, 421, "Misdirected Request"),
+    (UnprocessableContent, 422, "Unprocessable Content"),
+    (Locked, 423, "Locked"),
+    (FailedDependency, 424, "Failed Dependency"),
+    (TooEarly, 425, "Too Early"),
+    (UpgradeRequired, 426, "Upgrade Required"),
+    (PreconditionRequired, 428, "Precondition Required"),
+    (TooManyRequests, 429, "Too Many Requests"),
+    (RequestHeaderFieldsTooLarge, 431, "Request Header Fields Too Large"),
+    (UnavailableForLegalReasons, 451, "Unavailable For Legal Reasons"),
+    (InternalServer, 500, "Internal Server Error"),
+    (NotImplemented, 501, "Not Implemented"),
+    (BadGateway, 502, "Bad Gateway"),
+    (ServiceUnavailable, 503, "Service Unavailable"),
+    (GatewayTimeout, 504, "Gateway Timeout"),
+    (HttpVersionNotSupported, 505, "HTTP Version Not Supported"),
+    (VariantAlsoNegotiates, 506, "Variant Also Negotiates"),
+    (InsufficientStorage, 507, "Insufficient Storage"),
+    (LoopDetected, 508, "Loop Detected"),
+    (NotExtended, 510, "Not Extended"),
+    (NetworkAuthenticationRequired, 511, "Network Authentication Required"),
+}
+
+/// Code and reason phrase of a `StatusCode::Custom`. Its fields are private, so the status line
+/// can not be built with values that were not validated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomStatusCode {
+    code: u16,
+    reason_phrase: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatusCodeError {
+    /// Status codes have three digits
+    OutOfRange(u16),
+    /// Control characters such as CR and LF would end the status line early, letting the
+    /// reason phrase inject headers or a whole response
+    InvalidReasonPhrase,
+}
+
+impl std::fmt::Display for StatusCodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatusCodeError::OutOfRange(code) => {
+                write!(f, "Status code {} is not between 100 and 999", code)
+            }
+            StatusCodeError::InvalidReasonPhrase => {
+                write!(f, "Reason phrase contains control characters")
+            }
+        }
+    }
+}
+
+impl StatusCode {
+    /// It creates a status code with a custom reason phrase, e.g. for codes not in the registry.
+    /// It fails when the code does not have three digits or the reason phrase contains control
+    /// characters other than tabs. A registered code with its own reason phrase gives the
+    /// registered variant, so `custom(200, "OK")` equals `StatusCode::Ok`.
+    pub fn custom(code: u16, reason_phrase: &str) -> Result<Self, StatusCodeError> {
+        if !(100..=999).contains(&code) {
+            return Err(StatusCodeError::OutOfRange(code));
+        }
+
+        if reason_phrase.chars().any(|c| c.is_control() && c != '\t') {
+            return Err(StatusCodeError::InvalidReasonPhrase);
+        }
+
+        if let Some(status_code) = StatusCode::from_u16(code) {
+            if status_code.reason_phrase() == reason_phrase {
+                return Ok(status_code);
+            }
+        }
+
+        Ok(StatusCode::Custom(CustomStatusCode {
+            code,
+            reason_phrase: reason_phrase.to_string(),
+        }))
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.as_u16())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.as_u16())
+    }
+
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.as_u16())
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.as_u16())
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.as_u16())
+    }
+
+    /// Responses with these codes never carry a body, so no `Content-Length` is sent either
+    pub fn allows_body(&self) -> bool {
+        !self.is_informational() && !matches!(self.as_u16(), 204 | 304)
+    }
+}
+
+impl std::fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.as_u16(), self.reason_phrase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_creates_custom_status_codes() {
+        let status_code = StatusCode::custom(299, "Mostly OK").unwrap();
+
+        assert_eq!(status_code.to_string(), "299 Mostly OK");
+        assert!(StatusCode::custom(100, "").is_ok());
+        assert!(StatusCode::custom(999, "Tab\tallowed").is_ok());
+    }
+
+    #[test]
+    fn it_returns_the_registered_variant_for_its_own_reason_phrase() {
+        assert_eq!(StatusCode::custom(200, "OK"), Ok(StatusCode::Ok));
+        assert_eq!(
+            StatusCode::custom(404, "Not Found"),
+            Ok(StatusCode::NotFound)
+        );
+
+        let status_code = StatusCode::custom(200, "Fine").unwrap();
+
+        assert_ne!(status_code, StatusCode::Ok);
+        assert_eq!(status_code.as_u16(), 200);
+        assert_eq!(status_code.to_string(), "200 Fine");
+    }
+
+    #[test]
+    fn it_rejects_custom_status_codes_out_of_range() {
+        for code in [0, 99, 1000, u16::MAX] {
+            assert_eq!(
+                StatusCode::custom(code, "Reason"),
+                Err(StatusCodeError::OutOfRange(code))
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_reason_phrases_with_control_characters() {
+        for reason_phrase in ["OK\r\nSet-Cookie: a=b", "OK\n", "OK\r", "OK\0", "OK\x7f"] {
+            assert_eq!(
+                StatusCode::custom(200, reason_phrase),
+                Err(StatusCodeError::InvalidReasonPhrase)
+            );
+        }
+    }
+}
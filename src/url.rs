@@ -0,0 +1,223 @@
+/// Parameters of a query string. Repeated keys are preserved in the order they were sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryParams(Vec<(String, String)>);
+
+impl QueryParams {
+    /// It parses a form-style query string (`a=1&b=hello+world`), where `+` stands for a space
+    pub fn parse(query: &str) -> Self {
+        let params = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+                (percent_decode_query(key), percent_decode_query(value))
+            })
+            .collect();
+
+        Self(params)
+    }
+
+    /// It returns the first value sent for the key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// It returns every value sent for the key
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.0.iter().any(|(k, _)| k == key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// It collapses repeated slashes of a path, e.g. `/echo//abc` becomes `/echo/abc`
+pub fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+
+    for c in path.chars() {
+        if c == '/' && merged.ends_with('/') {
+            continue;
+        }
+
+        merged.push(c);
+    }
+
+    merged
+}
+
+/// It resolves the `.` and `..` segments of an absolute path as described in RFC 3986, e.g.
+/// `/files/../x` becomes `/x`. A `..` never goes above the root.
+pub fn remove_dot_segments(path: &str) -> String {
+    let Some(relative_path) = path.strip_prefix('/') else {
+        return path.to_string();
+    };
+
+    let segments: Vec<&str> = relative_path.split('/').collect();
+    let mut output: Vec<&str> = vec![];
+
+    for (position, &segment) in segments.iter().enumerate() {
+        let is_last = position == segments.len() - 1;
+
+        match segment {
+            "." | ".." => {
+                if segment == ".." {
+                    output.pop();
+                }
+
+                // `/a/b/..` points to the directory, so it keeps the trailing slash
+                if is_last {
+                    output.push("");
+                }
+            }
+            _ => output.push(segment),
+        }
+    }
+
+    format!("/{}", output.join("/"))
+}
+
+/// It decodes `%XX` sequences of a path. Malformed sequences are kept as they are.
+pub fn percent_decode(value: &str) -> String {
+    decode(value, false)
+}
+
+/// It decodes `%XX` sequences of a query component, where `+` is also decoded as a space
+pub fn percent_decode_query(value: &str) -> String {
+    decode(value, true)
+}
+
+/// It encodes a value to be used as a single path segment. Only unreserved characters are kept
+/// as they are, so `/`, `?` and `#` can not change the meaning of the path.
+pub fn percent_encode(value: &str) -> String {
+    // Dot segments would be resolved as the current or parent directory
+    if value == "." || value == ".." {
+        return value.replace('.', "%2E");
+    }
+
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char);
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+/// It encodes the characters that can not appear in the path or query of a URI, e.g. to send a
+/// target back in a `Location` header. Existing `%XX` sequences are kept as they are.
+pub fn percent_encode_target(target: &str) -> String {
+    let mut encoded = String::with_capacity(target.len());
+
+    for byte in target.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            // Unreserved, sub-delims and the delimiters allowed in paths and queries
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' | b':' | b'@' | b'/' | b'?' | b'%' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+fn decode(value: &str, plus_as_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let hex = bytes
+                    .get(index + 1..index + 3)
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+                match hex {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        index += 3;
+
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+
+        index += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_encodes_control_characters_and_spaces_of_a_target() {
+        assert_eq!(
+            percent_encode_target("/a\nSet-Cookie:evil=1"),
+            "/a%0ASet-Cookie:evil=1"
+        );
+        assert_eq!(
+            percent_encode_target("/a b/\r\n?q=\"x\""),
+            "/a%20b/%0D%0A?q=%22x%22"
+        );
+        assert_eq!(percent_encode_target("/caf%C3%A9/é"), "/caf%C3%A9/%C3%A9");
+    }
+
+    #[test]
+    fn it_merges_repeated_slashes() {
+        assert_eq!(merge_slashes("/echo//abc"), "/echo/abc");
+        assert_eq!(merge_slashes("//x"), "/x");
+        assert_eq!(merge_slashes("/a///b/"), "/a/b/");
+        assert_eq!(merge_slashes("/"), "/");
+    }
+
+    #[test]
+    fn it_removes_dot_segments() {
+        assert_eq!(remove_dot_segments("/a/b/.."), "/a/");
+        assert_eq!(remove_dot_segments("/a/b/../c"), "/a/c");
+        assert_eq!(remove_dot_segments("/a/./b/."), "/a/b/");
+        assert_eq!(remove_dot_segments("/.."), "/");
+        assert_eq!(remove_dot_segments("/../../x"), "/x");
+        assert_eq!(remove_dot_segments("/a/..b/c."), "/a/..b/c.");
+        assert_eq!(remove_dot_segments("*"), "*");
+    }
+
+    #[test]
+    fn it_keeps_empty_segments_when_removing_dot_segments() {
+        // Merging slashes is a separate option, so `//` can still be left after `..`
+        assert_eq!(remove_dot_segments("/..//x"), "//x");
+        assert_eq!(remove_dot_segments("/a//../b"), "/a/b");
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use crate::{
+    handler::{self, HandlerPattern, Route},
+    method::Method,
+    request::Request,
+    response::{IntoResponse, Response},
+    router::{RouteError, RouteInfo, RouteTree, Router, UrlError},
+    url,
+};
+
+/// A site served for the requests addressed to a host name, with its own routes and public
+/// folder. The host is either an exact name such as `example.test` or a wildcard such as
+/// `*.example.test`, which matches any subdomain but not `example.test` itself.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualHost {
+    host: Option<String>,
+    pub(crate) routes: RouteTree,
+    pub(crate) public_folder: Option<String>,
+}
+
+impl VirtualHost {
+    /// It creates the virtual host for the host name, which is matched without case and without
+    /// the trailing dot. It panics when the name is not valid, as the routes would never be
+    /// served.
+    pub(crate) fn new(host: &str) -> Self {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let domain = host.strip_prefix("*.").unwrap_or(&host);
+
+        // Only IPv6 addresses, enclosed in brackets, contain colons
+        let is_ipv6 = domain.starts_with('[') && domain.ends_with(']');
+
+        if domain.is_empty() || domain.contains(['*', '/']) || (domain.contains(':') && !is_ipv6) {
+            panic!("Invalid virtual host {:?}", host);
+        }
+
+        Self {
+            host: Some(host),
+            ..Self::default()
+        }
+    }
+
+    /// It returns the host name of the virtual host, `None` for the default one
+    pub fn get_host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// It registers a handler and returns its route, like `ServerHTTP::handle_fn`
+    pub fn handle_fn<F>(&mut self, method: Method, path: &str, handler_fn: F) -> &mut Route
+    where
+        F: Fn(Request, Response) + Send + Sync + 'static,
+    {
+        let handler_pattern = HandlerPattern(method, path.to_string());
+
+        self.add_route(handler_pattern, Route::new(Arc::new(handler_fn)))
+    }
+
+    /// It registers a handler that returns its response, like `ServerHTTP::handle`
+    pub fn handle<F, E>(&mut self, method: Method, path: &str, handler: F) -> &mut Route
+    where
+        F: Fn(Request) -> Result<Response, E> + Send + Sync + 'static,
+        E: IntoResponse,
+    {
+        let handler_pattern = HandlerPattern(method, path.to_string());
+
+        self.add_route(
+            handler_pattern,
+            Route::new(handler::returning_handler(handler)),
+        )
+    }
+
+    /// It registers every route of the router as it is. It panics when a route conflicts with
+    /// one already registered.
+    pub fn merge(&mut self, router: Router) -> &mut Self {
+        for (handler_pattern, route) in router.into_routes() {
+            self.add_route(handler_pattern, route);
+        }
+
+        self
+    }
+
+    /// It registers every route of the router under the prefix
+    pub fn nest(&mut self, prefix: &str, router: Router) -> &mut Self {
+        let mut prefixed_router = Router::new();
+        prefixed_router.nest(prefix, router);
+
+        self.merge(prefixed_router)
+    }
+
+    /// It sets the folder that handlers of this host get in `res.public_folder`
+    pub fn set_public_folder(&mut self, public_folder: &str) -> &mut Self {
+        self.public_folder = Some(public_folder.to_string());
+
+        self
+    }
+
+    /// It builds the path of the route registered with the name, like `ServerHTTP::url_for`
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        self.routes.url_for(name, params)
+    }
+
+    /// It lists the registered routes sorted by path and method
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        let mut routes: Vec<RouteInfo> = self
+            .routes
+            .routes()
+            .into_iter()
+            .map(|(handler_pattern, route)| RouteInfo {
+                host: self.host.clone(),
+                method: handler_pattern.get_method(),
+                path: handler_pattern.get_path(),
+                name: route.name.clone(),
+                middlewares: route.middlewares.len(),
+            })
+            .collect();
+
+        routes.sort_by(|a, b| (&a.path, &a.method).cmp(&(&b.path, &b.method)));
+
+        routes
+    }
+
+    /// It registers a handler that returns its response, returning the error instead of
+    /// panicking when the route is not valid, e.g. for routes read from a configuration file
+    pub(crate) fn try_handle<F, E>(
+        &mut self,
+        method: Method,
+        path: &str,
+        handler: F,
+    ) -> Result<&mut Route, RouteError>
+    where
+        F: Fn(Request) -> Result<Response, E> + Send + Sync + 'static,
+        E: IntoResponse,
+    {
+        let handler_pattern = HandlerPattern(method, path.to_string());
+
+        self.routes.insert(
+            handler_pattern,
+            Route::new(handler::returning_handler(handler)),
+        )
+    }
+
+    fn add_route(&mut self, handler_pattern: HandlerPattern, route: Route) -> &mut Route {
+        match self.routes.insert(handler_pattern, route) {
+            Ok(route) => route,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// It tells if the virtual host serves the host name, given in lowercase and without port
+    pub(crate) fn matches(&self, host: &str) -> bool {
+        let Some(pattern) = &self.host else {
+            return true;
+        };
+
+        match pattern.strip_prefix('*') {
+            // The suffix keeps its leading dot, so `*.example.test` needs a subdomain
+            Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+            None => pattern == host,
+        }
+    }
+
+    /// It tells how specific the host pattern is, so exact names are tried before wildcards and
+    /// longer wildcards before shorter ones
+    pub(crate) fn specificity(&self) -> (bool, usize) {
+        match &self.host {
+            Some(host) => (!host.starts_with('*'), host.len()),
+            None => (false, 0),
+        }
+    }
+
+    /// It returns the route serving the method for the path, with its parameters
+    pub(crate) fn find_route(
+        &self,
+        raw_path: &str,
+        method: &Method,
+    ) -> Option<(&Route, HashMap<String, String>)> {
+        self.routes.find_route(&path_segments(raw_path), method)
+    }
+
+    /// It tells if any route matches the path, whatever its method
+    pub(crate) fn has_route(&self, raw_path: &str) -> bool {
+        self.routes.has_match(&path_segments(raw_path))
+    }
+
+    /// It returns the methods of every route matching the path
+    pub(crate) fn matching_methods(&self, raw_path: &str) -> BTreeSet<Method> {
+        self.routes.matching_methods(&path_segments(raw_path))
+    }
+}
+
+fn path_segments(raw_path: &str) -> Vec<String> {
+    // The first segment is the empty one before the leading '/'
+    raw_path
+        .split('/')
+        .skip(1)
+        .map(url::percent_decode)
+        .collect()
+}
@@ -0,0 +1,169 @@
+use std::{
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// A fixed number of threads running the same handler over the items submitted to them. Items
+/// wait in a bounded queue until a worker is free, so the resources used by the pool do not grow
+/// with the load. Dropping the pool closes the queue, and the workers stop once they finish the
+/// items already queued.
+pub(crate) struct WorkerPool<T> {
+    sender: SyncSender<T>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// It starts the workers. A `queue_depth` of 0 only accepts items when a worker is idle.
+    pub fn new<F>(workers: usize, queue_depth: usize, handler: F) -> io::Result<Self>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
+
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        for _ in 0..workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let handler = Arc::clone(&handler);
+
+            thread::Builder::new()
+                .name("http-worker".to_string())
+                .spawn(move || run_worker(&receiver, handler.as_ref()))?;
+        }
+
+        Ok(Self { sender })
+    }
+
+    /// It queues the item for the next free worker. The item is given back when the queue is
+    /// full, so the caller decides what to do with it.
+    pub fn try_submit(&self, item: T) -> Result<(), T> {
+        self.sender.try_send(item).map_err(|err| match err {
+            TrySendError::Full(item) | TrySendError::Disconnected(item) => item,
+        })
+    }
+}
+
+fn run_worker<T>(receiver: &Mutex<Receiver<T>>, handler: &(dyn Fn(T) + Send + Sync)) {
+    loop {
+        // The lock is released as soon as an item is received, so other workers can wait for
+        // the next one while this one is busy
+        let item = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(err) => err.into_inner().recv(),
+        };
+
+        let Ok(item) = item else {
+            return;
+        };
+
+        // A panicking handler must not take the worker down with it, or the pool would shrink
+        if panic::catch_unwind(AssertUnwindSafe(|| handler(item))).is_err() {
+            eprintln!("Worker recovered from a panic while handling a connection");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// A job that tells when it started and then waits until it is released
+    struct Job {
+        started: mpsc::Sender<()>,
+        release: Receiver<()>,
+    }
+
+    fn job() -> (Job, Receiver<()>, mpsc::Sender<()>) {
+        let (started, started_receiver) = mpsc::channel();
+        let (release_sender, release) = mpsc::channel();
+
+        (Job { started, release }, started_receiver, release_sender)
+    }
+
+    fn pool(workers: usize, queue_depth: usize) -> WorkerPool<Job> {
+        WorkerPool::new(workers, queue_depth, |job: Job| {
+            job.started.send(()).unwrap();
+            let _ = job.release.recv();
+        })
+        .unwrap()
+    }
+
+    /// A worker may not be waiting for items yet, or again after finishing one, so the pool is
+    /// given some time to take the item
+    fn submit_when_idle<T: Send + 'static>(pool: &WorkerPool<T>, mut item: T) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while let Err(rejected_item) = pool.try_submit(item) {
+            assert!(Instant::now() < deadline, "No worker became idle");
+
+            item = rejected_item;
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn it_gives_the_item_back_when_the_queue_is_full() {
+        let pool = pool(1, 1);
+
+        let (first, first_started, release_first) = job();
+        let (second, second_started, _release_second) = job();
+        let (third, _, _) = job();
+
+        assert!(pool.try_submit(first).is_ok());
+        first_started.recv().unwrap();
+
+        // The worker is busy, so the second job waits in the queue and the third one does not fit
+        assert!(pool.try_submit(second).is_ok());
+        let third = pool.try_submit(third).unwrap_err();
+
+        release_first.send(()).unwrap();
+        second_started.recv().unwrap();
+
+        assert!(pool.try_submit(third).is_ok());
+    }
+
+    #[test]
+    fn it_only_accepts_items_while_a_worker_is_idle_without_queue() {
+        let pool = pool(1, 0);
+
+        let (first, first_started, release_first) = job();
+        let (second, second_started, _release_second) = job();
+
+        submit_when_idle(&pool, first);
+        first_started.recv().unwrap();
+
+        let second = pool.try_submit(second).unwrap_err();
+
+        release_first.send(()).unwrap();
+
+        submit_when_idle(&pool, second);
+        second_started.recv().unwrap();
+    }
+
+    #[test]
+    fn it_keeps_the_worker_after_a_handler_panics() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+
+        let pool = WorkerPool::new(1, 1, move |item: u32| {
+            if item == 0 {
+                panic!("Handler panic");
+            }
+
+            sender.lock().unwrap().send(item).unwrap();
+        })
+        .unwrap();
+
+        assert!(pool.try_submit(0).is_ok());
+        submit_when_idle(&pool, 1);
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(1));
+    }
+}